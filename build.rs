@@ -1,10 +1,101 @@
 use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[path = "build/config.rs"]
+mod config;
+
+use config::{
+    irq_linker_fragment, parse_addr, parse_custom_opcode, target_cfgs, DEFAULT_VECTOR_ADDR,
+};
+
+// Supported configurations:
+//
+// | Target            | Features | Notes                                 |
+// |-------------------|----------|---------------------------------------|
+// | `riscv32*`        | any      | Instructions emitted with `asm!`      |
+// | `riscv32e*`       | any      | As above, only x0 to x15 are used     |
+// | `riscv64*`        | any      | Same instructions, values stay 32-bit |
+// | not RISC-V (host) | any      | Instruction wrappers are stubs        |
+//
+// Any other RISC-V target fails the build here rather than silently falling
+// back to the stubs.
 
 fn main() {
+    println!("cargo:rustc-check-cfg=cfg(riscv)");
+    println!("cargo:rustc-check-cfg=cfg(riscv32)");
+    println!("cargo:rustc-check-cfg=cfg(riscv64)");
+    println!(
+        "cargo:rustc-check-cfg=cfg(coucal_opcode, \
+         values(\"custom0\", \"custom1\", \"custom2\", \"custom3\"))"
+    );
+
     let target = env::var("TARGET").unwrap();
 
-    if target.starts_with("riscv32") {
-        println!("cargo:rustc-cfg=riscv");
-        println!("cargo:rustc-cfg=riscv32");
+    let cfgs = target_cfgs(&target).unwrap_or_else(|| {
+        panic!(
+            "unsupported target `{}`: use a `riscv32*` or `riscv64*` target",
+            target
+        )
+    });
+
+    for cfg in cfgs {
+        println!("cargo:rustc-cfg={}", cfg);
     }
+
+    select_custom_opcode();
+    write_irq_linker_fragment();
+}
+
+/// Sets the `coucal_opcode` cfg from `COUCAL_CUSTOM_OPCODE`
+///
+/// PicoRV32 puts its interrupt instructions on `CUSTOM_0`, but an SoC can
+/// move them to another custom opcode when `CUSTOM_0` is used by something
+/// else. The variable takes the number of the custom opcode, `0` to `3`,
+/// optionally written as `custom0` to `custom3`.
+fn select_custom_opcode() {
+    println!("cargo:rerun-if-env-changed=COUCAL_CUSTOM_OPCODE");
+
+    let opcode = match env::var("COUCAL_CUSTOM_OPCODE") {
+        Ok(value) => parse_custom_opcode(&value).unwrap_or_else(|| {
+            panic!(
+                "invalid COUCAL_CUSTOM_OPCODE `{}`: expected 0 to 3 or \
+                 custom0 to custom3",
+                value
+            )
+        }),
+        Err(_) => 0,
+    };
+
+    println!("cargo:rustc-cfg=coucal_opcode=\"custom{}\"", opcode);
+}
+
+/// Writes `coucal-irq.x` to the output directory and adds it to the linker
+/// search path
+///
+/// The fragment places the `.irq_vector` section at `COUCAL_VECTOR_ADDR`,
+/// which must match the `PROGADDR_IRQ` the core was built with.
+fn write_irq_linker_fragment() {
+    println!("cargo:rerun-if-env-changed=COUCAL_VECTOR_ADDR");
+
+    let vector_addr = match env::var("COUCAL_VECTOR_ADDR") {
+        Ok(value) => parse_addr(&value).unwrap_or_else(|| {
+            panic!(
+                "invalid COUCAL_VECTOR_ADDR `{}`: expected a decimal or \
+                 0x-prefixed hexadecimal address",
+                value
+            )
+        }),
+        Err(_) => DEFAULT_VECTOR_ADDR,
+    };
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    fs::write(
+        out_dir.join("coucal-irq.x"),
+        irq_linker_fragment(vector_addr),
+    )
+    .unwrap();
+
+    println!("cargo:rustc-link-search={}", out_dir.display());
 }
@@ -0,0 +1,71 @@
+//! Parsing and code generation for the build script
+//!
+//! These functions don't touch the environment, so `tests/build_config.rs`
+//! can include this file and test them directly.
+
+/// Default interrupt vector address, matching the PicoRV32 `PROGADDR_IRQ`
+/// default
+pub const DEFAULT_VECTOR_ADDR: u32 = 0x10;
+
+/// Returns the cfgs to set for the target triple `target`
+///
+/// Any RISC-V target other than `riscv32*` and `riscv64*` is unsupported and
+/// gives `None`. Every other target is a host, with no cfgs.
+pub fn target_cfgs(target: &str) -> Option<&'static [&'static str]> {
+    if target.starts_with("riscv32") {
+        Some(&["riscv", "riscv32"])
+    } else if target.starts_with("riscv64") {
+        Some(&["riscv", "riscv64"])
+    } else if target.starts_with("riscv") {
+        None
+    } else {
+        Some(&[])
+    }
+}
+
+/// Parses a `COUCAL_CUSTOM_OPCODE` value, `0` to `3` or `custom0` to
+/// `custom3`, into the number of the custom opcode
+pub fn parse_custom_opcode(value: &str) -> Option<u8> {
+    let value = value.trim().to_ascii_lowercase();
+    let number = value
+        .strip_prefix("custom")
+        .map(|n| n.trim_start_matches('_'))
+        .unwrap_or(&value);
+
+    match number.parse() {
+        Ok(n) if n <= 3 => Some(n),
+        _ => None,
+    }
+}
+
+/// Parses a `COUCAL_VECTOR_ADDR` value, in decimal or `0x`-prefixed
+/// hexadecimal, with optional `_` separators
+pub fn parse_addr(value: &str) -> Option<u32> {
+    let value = value.trim().replace('_', "");
+
+    if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// Returns the contents of `coucal-irq.x` for a vector at `vector_addr`
+pub fn irq_linker_fragment(vector_addr: u32) -> String {
+    format!(
+        "/* Generated by the coucal-rs build script */
+
+SECTIONS
+{{
+  .irq_vector {:#010x} :
+  {{
+    KEEP(*(.irq_vector));
+  }}
+}}
+",
+        vector_addr
+    )
+}
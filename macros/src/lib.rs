@@ -0,0 +1,100 @@
+//! Attribute macros for `coucal-rs`
+//!
+//! Use these through the `macros` feature of `coucal-rs`, which re-exports
+//! them. The generated code refers to `coucal-rs` as `::coucal_rs`, so the
+//! dependency must not be renamed.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Error, FnArg, ItemFn, ReturnType, Type};
+
+/// Makes `fn(u32)` the interrupt handler of the program
+///
+/// ```ignore
+/// #[coucal_rs::interrupt]
+/// fn on_irq(irqs: u32) {
+///     for line in coucal_rs::interrupt::pending_lines(irqs) {
+///         // ..
+///     }
+/// }
+/// ```
+///
+/// This generates the same entry as `interrupt_vector!`, but the entry calls
+/// the annotated function once with the bitmask of pending IRQs instead of
+/// dispatching to a handler per line. The registers are saved before the
+/// call and restored after it, and the entry returns with `retirq`, so the
+/// function itself is ordinary Rust. `interrupt::in_interrupt` returns
+/// `true` while it runs.
+///
+/// The function must take a single `u32` and return nothing, and can't be
+/// `async`, `const`, `unsafe`, `extern` or generic. Either this attribute or
+/// `interrupt_vector!` can be used, once, in a program.
+#[proc_macro_attribute]
+pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return Error::new(Span::call_site(), "`#[interrupt]` takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    let handler = parse_macro_input!(input as ItemFn);
+
+    if let Err(e) = check_signature(&handler) {
+        return e.to_compile_error().into();
+    }
+
+    let name = &handler.sig.ident;
+
+    quote!(
+        #handler
+
+        #[allow(dead_code)]
+        extern "C" fn __coucal_irq_dispatch(pending: u32) {
+            ::coucal_rs::interrupt::__vector_handle(pending, #name);
+        }
+
+        ::coucal_rs::__irq_entry!(__coucal_irq_dispatch);
+    )
+    .into()
+}
+
+/// Checks that `handler` can be called as `fn(u32)`
+fn check_signature(handler: &ItemFn) -> Result<(), Error> {
+    let sig = &handler.sig;
+    let wrong = |span| {
+        Error::new(
+            span,
+            "`#[interrupt]` handlers must have the signature `fn(u32)`",
+        )
+    };
+
+    if sig.asyncness.is_some()
+        || sig.constness.is_some()
+        || sig.unsafety.is_some()
+        || sig.abi.is_some()
+        || sig.variadic.is_some()
+        || !sig.generics.params.is_empty()
+        || sig.generics.where_clause.is_some()
+    {
+        return Err(wrong(sig.span()));
+    }
+
+    if let ReturnType::Type(_, ty) = &sig.output {
+        match &**ty {
+            Type::Tuple(unit) if unit.elems.is_empty() => {}
+            _ => return Err(wrong(ty.span())),
+        }
+    }
+
+    let mut inputs = sig.inputs.iter();
+
+    match (inputs.next(), inputs.next()) {
+        (Some(FnArg::Typed(arg)), None) => match &*arg.ty {
+            Type::Path(ty) if ty.qself.is_none() && ty.path.is_ident("u32") => Ok(()),
+            ty => Err(wrong(ty.span())),
+        },
+        _ => Err(wrong(sig.span())),
+    }
+}
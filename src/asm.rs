@@ -13,7 +13,9 @@
 //
 // From the [picorv32 manual](https://github.com/YosysHQ/picorv32):
 //
-//     opcode = 0b0001011 [CUSTOM_0]  (Opcode names are not supported)
+//     opcode = 0b0001011 [CUSTOM_0]  (Opcode names are not supported. The
+//                                     build can select another custom opcode,
+//                                     see `OPCODE`)
 //     func3  = ---                   (Don't care)
 //     func7  = Specifies the instruction
 //     rd     = Compiler allocated destination register
@@ -26,119 +28,216 @@
 // This method frees the compiler to allocate registers and specify them in
 // the assembly.
 //
+// The instructions are the same on RV32 and RV64 cores. The IRQ mask, the
+// pending bitmask, the timer counter and the q-registers are 32 bits wide on
+// both, so the wrappers take and return `u32` even on RV64 targets. The upper
+// half of a register is not meaningful there.
+//
+// RV32E cores only have `x0` to `x15`. The compiler allocated registers stay
+// within that set on `riscv32e*` targets, and the q-register wrappers only
+// name `x0` to `x3`, so every wrapper also works there.
+//
 // Each instruction wrapper will set func7, rd and rs1 correctly for their
-// instruction.
+// instruction. Most of them do so through the `insn_r!` macro. The q-register
+// wrappers can't, as the macro only takes compiler allocated registers.
 
+#[cfg(riscv)]
 use core::arch::asm;
+use core::fmt;
+use core::marker::PhantomData;
+#[cfg(any(feature = "counters", not(riscv)))]
+use core::sync::atomic::{compiler_fence, Ordering};
 
-/// `getq` instruction wrapper (`getq __, q2`)
+/// `getq` instruction wrapper (`getq __, qN`)
 ///
-/// This function returns the value from the `q2` q-register.
+/// This function returns the value from the q-register at offset `N`. `N`
+/// must be less than 4, which is checked at compile time.
 ///
 /// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - The safety requirements of the matching `getq0` to `getq3` wrapper
+///   apply
 #[inline]
 #[allow(unused_variables)]
 #[cfg(feature = "interrupts-qregs")]
-pub unsafe fn getq2() -> u32 {
+pub unsafe fn getq<const N: u32>() -> u32 {
+    const { assert!(N < 4, "PicoRV32 only has q-registers q0 to q3") };
+
     match () {
         #[cfg(riscv)]
         () => {
             let ret: u32;
 
-            // The picorv32 getq2 specific values:
+            // The picorv32 getq specific values:
             //
             //     func7 = 0b0000000
             //     rd    = ret register
-            //     rs1   = q2           (x2 used in place)
+            //     rs1   = qN           (xN used in place)
             //
             // NOTE: The `.insn` requires a register name for `rs1` but the
             // compiler is not aware of the `q` registers as they are picorv32
             // specific. To work around this we use the `x` register
-            // equivalents. In this case we want to read from `q2` which is
-            // offset 2, therefore we use `x2` to achieve this.
+            // equivalents. The const operand pastes `N` into the register
+            // name, so reading `q2` uses `x2`.
             asm!(
-                ".insn r 0b0001011, 0, 0b0000000, {0}, x2, zero",
+                ".insn r {2}, 0, {3}, {0}, x{1}, zero",
                 out(reg) ret,
+                const N,
+                const OPCODE,
+                const FUNC7_GETQ,
                 );
 
             ret
         }
 
-        #[cfg(not(riscv))]
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::getq(N),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
         () => unimplemented!(),
     }
 }
 
-/// `getq` instruction wrapper (`getq __, q3`)
+/// `setq` instruction wrapper (`setq __, qN`)
 ///
-/// This function returns the value from the `q3` q-register.
+/// This function writes val to the q-register at offset `N`. `N` must be
+/// less than 4, which is checked at compile time.
 ///
 /// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - Do not overwrite `qN` while an interrupt handler relies on its contents
 #[inline]
 #[allow(unused_variables)]
 #[cfg(feature = "interrupts-qregs")]
-pub unsafe fn getq3() -> u32 {
+pub unsafe fn setq<const N: u32>(val: u32) {
+    const { assert!(N < 4, "PicoRV32 only has q-registers q0 to q3") };
+
     match () {
         #[cfg(riscv)]
         () => {
-            let ret: u32;
-
-            // The picorv32 getq3 specific values:
+            // The picorv32 setq specific values:
             //
-            //     func7 = 0b0000000
-            //     rd    = ret register
-            //     rs1   = q3           (x3 used in place)
+            //     func7 = 0b0000001
+            //     rd    = qN           (xN used in place)
+            //     rs1   = val register
             //
-            // NOTE: The `.insn` requires a register name for `rs1` but the
+            // NOTE: The `.insn` requires a register name for `rd` but the
             // compiler is not aware of the `q` registers as they are picorv32
             // specific. To work around this we use the `x` register
-            // equivalents. In this case we want to read from `q3` which is
-            // offset 3, therefore we use `x3` to achieve this.
+            // equivalents. The const operand pastes `N` into the register
+            // name, so writing `q2` uses `x2`.
             asm!(
-                ".insn r 0b0001011, 0, 0b0000000, {0}, x3, zero",
-                out(reg) ret,
+                ".insn r {2}, 0, {3}, x{1}, {0}, zero",
+                in(reg) val,
+                const N,
+                const OPCODE,
+                const FUNC7_SETQ,
                 );
-
-            ret
         }
 
-        #[cfg(not(riscv))]
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::setq(N, val),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
         () => unimplemented!(),
     }
 }
 
+/// `getq` instruction wrapper (`getq __, q0`)
+///
+/// This function returns the value from the `q0` q-register.
+///
+/// The core writes the return address of the interrupted code to `q0` when
+/// it enters the interrupt handler, and `retirq` jumps back to it. The value
+/// is only meaningful inside a handler, and is overwritten when the next
+/// interrupt is taken.
+///
+/// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - `q0` is managed by the core. Only rely on the value inside an
+///   interrupt handler, before anything else has written to `q0`
+#[inline]
+#[allow(unused_variables)]
+#[cfg(feature = "interrupts-qregs")]
+pub unsafe fn getq0() -> u32 {
+    getq::<0>()
+}
+
+/// `getq` instruction wrapper (`getq __, q1`)
+///
+/// This function returns the value from the `q1` q-register.
+///
+/// The core writes the bitmask of IRQs being serviced to `q1` when it enters
+/// the interrupt handler. The value is only meaningful inside a handler, and
+/// is overwritten when the next interrupt is taken.
+///
+/// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - `q1` is managed by the core. Only rely on the value inside an
+///   interrupt handler, before anything else has written to `q1`
+#[inline]
+#[allow(unused_variables)]
+#[cfg(feature = "interrupts-qregs")]
+pub unsafe fn getq1() -> u32 {
+    getq::<1>()
+}
+
+/// `getq` instruction wrapper (`getq __, q2`)
+///
+/// This function returns the value from the `q2` q-register.
+///
+/// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - The q-registers are shared scratch registers. The value read is only
+///   meaningful if the caller knows what last wrote to `q2`
+#[inline]
+#[allow(unused_variables)]
+#[cfg(feature = "interrupts-qregs")]
+pub unsafe fn getq2() -> u32 {
+    getq::<2>()
+}
+
+/// `getq` instruction wrapper (`getq __, q3`)
+///
+/// This function returns the value from the `q3` q-register.
+///
+/// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - The q-registers are shared scratch registers. The value read is only
+///   meaningful if the caller knows what last wrote to `q3`
+#[inline]
+#[allow(unused_variables)]
+#[cfg(feature = "interrupts-qregs")]
+pub unsafe fn getq3() -> u32 {
+    getq::<3>()
+}
+
 /// `setq` instruction wrapper (`setq __, q2`)
 ///
 /// This function writes val to the `q2` q-register.
 ///
 /// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - Do not overwrite `q2` while an interrupt handler relies on its contents
 #[inline]
 #[allow(unused_variables)]
 #[cfg(feature = "interrupts-qregs")]
-pub unsafe fn setq2(val: u32) -> () {
-    match () {
-        #[cfg(riscv)]
-        () => {
-            // The picorv32 setq2 specific values:
-            //
-            //     func7 = 0b0000001
-            //     rd    = q2           (x2 used in place)
-            //     rs1   = val register
-            //
-            // NOTE: The `.insn` requires a register name for `rd` but the
-            // compiler is not aware of the `q` registers as they are picorv32
-            // specific. To work around this we use the `x` register
-            // equivalents. In this case we want to write to `q2` which is
-            // offset 2, therefore we use `x2` to achieve this.
-            asm!(
-                ".insn r 0b0001011, 0, 0b0000001, x2, {0}, zero",
-                in(reg) val,
-                );
-        }
-
-        #[cfg(not(riscv))]
-        () => unimplemented!(),
-    }
+pub unsafe fn setq2(val: u32) {
+    setq::<2>(val)
 }
 
 /// `setq` instruction wrapper (`setq __, q3`)
@@ -146,42 +245,30 @@ pub unsafe fn setq2(val: u32) -> () {
 /// This function writes val to the `q3` q-register.
 ///
 /// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - Do not overwrite `q3` while an interrupt handler relies on its contents
 #[inline]
 #[allow(unused_variables)]
 #[cfg(feature = "interrupts-qregs")]
-pub unsafe fn setq3(val: u32) -> () {
-    match () {
-        #[cfg(riscv)]
-        () => {
-            // The picorv32 setq3 specific values:
-            //
-            //     func7 = 0b0000001
-            //     rd    = q3           (x3 used in place)
-            //     rs1   = val register
-            //
-            // NOTE: The `.insn` requires a register name for `rd` but the
-            // compiler is not aware of the `q` registers as they are picorv32
-            // specific. To work around this we use the `x` register
-            // equivalents. In this case we want to write to `q3` which is
-            // offset 3, therefore we use `x3` to achieve this.
-            asm!(
-                ".insn r 0b0001011, 0, 0b0000001, x3, {0}, zero",
-                in(reg) val,
-                );
-        }
-
-        #[cfg(not(riscv))]
-        () => unimplemented!(),
-    }
+pub unsafe fn setq3(val: u32) {
+    setq::<3>(val)
 }
 
 /// `retirq` instruction wrapper
 ///
 /// Return from interrupt. This function resets the program counter to the
-/// last value before the interrupt and re-enables interrupts.
+/// last value before the interrupt and re-enables interrupts. It is the last
+/// thing an interrupt handler does, and never returns.
+///
+/// # Safety
+///
+/// - Only call this function at the end of an interrupt handler, once the
+///   interrupted context has been restored
 #[inline]
 #[allow(unused_variables)]
-pub unsafe fn retirq() -> () {
+pub unsafe fn retirq() -> ! {
     match () {
         #[cfg(riscv)]
         () => {
@@ -190,8 +277,14 @@ pub unsafe fn retirq() -> () {
             //     func7 = 0b0000010
             //     rd    = -----        (Don't care so set to zero [x0])
             //     rs1   = -----        (Don't care so set to zero [x0])
+            //
+            // Written out rather than with `insn_r!`, which can't pass the
+            // `noreturn` option.
             asm!(
-                ".insn r 0b0001011, 0, 0b0000010, zero, zero, zero",
+                ".insn r {0}, 0, {1}, zero, zero, zero",
+                const OPCODE,
+                const FUNC7_RETIRQ,
+                options(noreturn),
                 );
         }
 
@@ -208,6 +301,11 @@ pub unsafe fn retirq() -> () {
 ///
 /// The mask argument is 32 bits. A `0b1` at any offset in `mask` disables
 /// that interrupt.
+///
+/// # Safety
+///
+/// - Unmasking interrupts inside an `interrupt::free` critical section breaks
+///   the guarantees of that critical section
 #[inline]
 #[allow(unused_variables)]
 pub unsafe fn maskirq(mask: u32) -> u32 {
@@ -221,27 +319,146 @@ pub unsafe fn maskirq(mask: u32) -> u32 {
             //     func7 = 0b0000011
             //     rd    = ret register
             //     rs1   = mask register
-            asm!(
-                ".insn r 0b0001011, 0, 0b0000011, {0}, {1}, zero",
-                out(reg) ret,
-                in(reg) mask,
-                );
+            insn_r!(FUNC7_MASKIRQ, out(reg) ret, in(reg) mask);
 
             ret
         }
 
-        #[cfg(not(riscv))]
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::maskirq(mask),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
         () => unimplemented!(),
     }
 }
 
+/// Restores the irq mask captured by `mask_scope` when dropped
+#[must_use = "the previous mask is restored as soon as the guard is dropped"]
+pub struct MaskGuard {
+    old_mask: u32,
+    // The guard restores the mask of the context that created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl MaskGuard {
+    /// Returns the mask that was in place before `mask_scope`, which is the
+    /// one restored on drop
+    pub fn old_mask(&self) -> u32 {
+        self.old_mask
+    }
+}
+
+impl Drop for MaskGuard {
+    fn drop(&mut self) {
+        unsafe {
+            maskirq(self.old_mask);
+        }
+    }
+}
+
+/// Writes `new_mask` to the irq mask register until the returned guard is
+/// dropped
+///
+/// This is `maskirq` with the restore done by the guard, so it also happens
+/// on an early return. Unlike `interrupt::enter`, any mask can be installed,
+/// not just all ones:
+///
+/// ```ignore
+/// // Keep the timer and the UART, mask everything else
+/// let _guard = unsafe { asm::mask_scope(!0b1001) };
+/// ```
+///
+/// Nested guards unwind in the reverse order they were created, each
+/// restoring the mask it captured.
+///
+/// # Safety
+///
+/// - Unmasking interrupts inside an `interrupt::free` critical section breaks
+///   the guarantees of that critical section. `new_mask` must not unmask any
+///   line there, and neither may a guard created before the critical section
+///   be dropped inside it
+/// - Nested guards must be dropped in the reverse order they were created
+#[inline]
+pub unsafe fn mask_scope(new_mask: u32) -> MaskGuard {
+    MaskGuard {
+        old_mask: maskirq(new_mask),
+        _not_send: PhantomData,
+    }
+}
+
+/// Reads the irq mask register without changing it
+///
+/// PicoRV32 has no instruction that only reads the mask, so this masks every
+/// interrupt with `maskirq` and immediately writes the old value back.
+///
+/// # Safety
+///
+/// - Every interrupt is briefly masked between the read and the write back.
+///   This adds to the interrupt latency and must not be relied on as a
+///   critical section
+#[inline]
+pub unsafe fn current_mask() -> u32 {
+    let old = maskirq(0xffff_ffff);
+    maskirq(old);
+
+    old
+}
+
+/// Masks the lines set in `bits`, leaving the others as they are, and
+/// returns the previous mask
+///
+/// The read and the write are a single logical operation: the first
+/// `maskirq` masks every interrupt while returning the old mask, and the
+/// second writes `old | bits`. No handler can run in between, even one on a
+/// line left unmasked by `interrupt::free_except`, so its own change to the
+/// mask can't be lost.
+#[inline]
+pub fn mask_or(bits: u32) -> u32 {
+    unsafe {
+        let old = maskirq(0xffff_ffff);
+        maskirq(old | bits);
+
+        old
+    }
+}
+
+/// Keeps only the masked lines that are also set in `bits`, and returns the
+/// previous mask
+///
+/// A line whose bit is clear in `bits` is unmasked. Like `mask_or`, the
+/// write of `old & bits` follows the read with every interrupt masked in
+/// between, so no handler can change the mask in the middle.
+///
+/// # Safety
+///
+/// - Unmasking interrupts inside an `interrupt::free` critical section breaks
+///   the guarantees of that critical section
+#[inline]
+pub unsafe fn mask_and(bits: u32) -> u32 {
+    let old = maskirq(0xffff_ffff);
+    maskirq(old & bits);
+
+    old
+}
+
 /// `waitirq` instruction wrapper
 ///
 /// This function blocks until an interrupt becomes pending. The bitmask of
 /// pending IRQs is returned.
 ///
 /// A `0b1` at any offset in the return value indicates an interrupt is
-/// pending on that interrupt line.
+/// pending on that interrupt line. Masked lines are included.
+///
+/// Reading the pending bitmask does not consume it. A pending bit is only
+/// cleared when the core enters the interrupt handler for that line, at
+/// which point it is reported in `q1`. Until then every call returns
+/// immediately with the bit still set, so this function can be called
+/// repeatedly to inspect the pending set. Lines configured as not latched
+/// (`LATCHED_IRQ` in the core) instead follow their input signal.
+///
+/// # Safety
+///
+/// - If no interrupt can become pending this function never returns
 #[inline]
 #[allow(unused_variables)]
 pub unsafe fn waitirq() -> u32 {
@@ -255,15 +472,15 @@ pub unsafe fn waitirq() -> u32 {
             //     func7 = 0b0000100
             //     rd    = ret register
             //     rs1   = -----        (Don't care so set to zero [x0])
-            asm!(
-                ".insn r 0b0001011, 0, 0b0000100, {0}, zero, zero",
-                out(reg) ret,
-                );
+            insn_r!(FUNC7_WAITIRQ, out(reg) ret, zero);
 
             ret
         }
 
-        #[cfg(not(riscv))]
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::waitirq(),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
         () => unimplemented!(),
     }
 }
@@ -276,8 +493,17 @@ pub unsafe fn waitirq() -> u32 {
 /// counter is returned.
 ///
 /// The timer interrupt is at offset 0 in the interrupt bitmask.
+///
+/// Note: this function is only available when the `timer` feature is enabled
+/// (the core is built with `ENABLE_IRQ_TIMER`).
+///
+/// # Safety
+///
+/// - Re-arming the timer discards any countdown another part of the program
+///   relies on
 #[inline]
 #[allow(unused_variables)]
+#[cfg(feature = "timer")]
 pub unsafe fn timer(cycles_to_wait: u32) -> u32 {
     match () {
         #[cfg(riscv)]
@@ -289,16 +515,771 @@ pub unsafe fn timer(cycles_to_wait: u32) -> u32 {
             //     func7 = 0b0000101
             //     rd    = ret register
             //     rs1   = cycles_to_wait register
+            insn_r!(FUNC7_TIMER, out(reg) ret, in(reg) cycles_to_wait);
+
+            ret
+        }
+
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::timer(cycles_to_wait),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
+        () => unimplemented!(),
+    }
+}
+
+/// `ebreak` instruction wrapper
+///
+/// On a core built with `CATCH_ILLINSN` and IRQ support this raises the trap
+/// interrupt on IRQ line 1. If that line is masked, or the core is built
+/// without IRQ support, the core instead enters its trap state and halts.
+/// Cores built with `ENABLE_TRACE` or attached to a debugger may behave
+/// differently, depending on the hardware configuration.
+///
+/// When the trap is taken `q0` points just past the `ebreak`, so returning
+/// from the handler resumes after it.
+///
+/// # Safety
+///
+/// - If line 1 is masked the core halts and this function never returns
+#[inline]
+pub unsafe fn ebreak() {
+    match () {
+        #[cfg(riscv)]
+        () => asm!("ebreak"),
+
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::ebreak(),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
+        () => unimplemented!(),
+    }
+}
+
+/// `nop` instruction wrapper
+///
+/// Useful for padding timing sensitive sequences such as bit-banged
+/// protocols. Off target this is only a compiler fence.
+#[inline]
+pub fn nop() {
+    match () {
+        #[cfg(riscv)]
+        () => unsafe { asm!("nop") },
+
+        #[cfg(not(riscv))]
+        () => compiler_fence(Ordering::SeqCst),
+    }
+}
+
+/// `fence` instruction wrapper
+///
+/// Orders every memory access before the fence against every access after
+/// it. PicoRV32 executes loads and stores in order, so on the core itself the
+/// instruction does very little. What matters is that the compiler treats it
+/// as a barrier: without it, plain (non volatile) accesses around an MMIO
+/// sequence, for example to a DMA buffer, may be moved across the device
+/// register writes. The instruction is still emitted so the ordering also
+/// holds on cores and interconnects that do reorder.
+///
+/// Off target this is only a compiler fence.
+#[inline]
+pub fn fence() {
+    match () {
+        #[cfg(riscv)]
+        () => unsafe { asm!("fence") },
+
+        #[cfg(not(riscv))]
+        () => compiler_fence(Ordering::SeqCst),
+    }
+}
+
+/// Semihosting `SYS_WRITE` call
+///
+/// Asks the attached debugger to write `len` bytes from `ptr` to its file
+/// descriptor `fd` (1 is the debugger's stdout). Returns the number of bytes
+/// that were not written, so zero on success.
+///
+/// The call uses the RISC-V semihosting convention: the operation number in
+/// `a0`, a pointer to the argument block in `a1`, and an `ebreak` framed by
+/// `slli x0, x0, 0x1f` and `srai x0, x0, 7` marker instructions, which must
+/// not be compressed.
+///
+/// This is meant for early bring-up with a debugger only. Without a debugger
+/// servicing the call, the `ebreak` raises the trap on IRQ line 1 like any
+/// other `ebreak`, or halts the core if that line can't be taken.
+///
+/// Note: this function is only available when the `semihosting` feature is
+/// enabled.
+///
+/// # Safety
+///
+/// - `ptr` must be valid for reads of `len` bytes
+/// - A debugger must service the call, or the trap handler on IRQ line 1
+///   must expect it
+#[inline]
+#[allow(unused_variables)]
+#[cfg(feature = "semihosting")]
+pub unsafe fn semihost_write(fd: u32, ptr: *const u8, len: usize) -> usize {
+    match () {
+        #[cfg(riscv)]
+        () => {
+            const SYS_WRITE: usize = 0x05;
+
+            let args = [fd as usize, ptr as usize, len];
+            let ret: usize;
+
             asm!(
-                ".insn r 0b0001011, 0, 0b0000101, {0}, {1}, zero",
-                out(reg) ret,
-                in(reg) cycles_to_wait,
+                ".option push",
+                ".option norvc",
+                "slli x0, x0, 0x1f",
+                "ebreak",
+                "srai x0, x0, 7",
+                ".option pop",
+                inout("a0") SYS_WRITE => ret,
+                in("a1") args.as_ptr(),
                 );
 
             ret
         }
 
-        #[cfg(not(riscv))]
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::semihost_write(ptr, len),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
+        () => unimplemented!(),
+    }
+}
+
+/// Cycles taken by one iteration of the `delay_cycles` loop
+const DELAY_LOOP_CYCLES: u32 = 8;
+
+/// Busy-waits for about `cycles` clock cycles without using the timer
+///
+/// The delay is a counted loop whose timing follows from the PicoRV32 cycle
+/// counts, so it leaves a running timer alone. Each iteration is:
+///
+/// ```text
+/// 1:  addi n, n, -1   // ALU reg + imm:  3 cycles
+///     bnez n, 1b      // taken branch:   5 cycles
+/// ```
+///
+/// The final branch is not taken, which saves 2 cycles, so `k` iterations
+/// take `8 * k - 2` cycles. The loop runs the number of iterations that
+/// comes closest to `cycles`, which is within 4 cycles of it. On top of that
+/// come a few cycles to set up the counter and check for a zero count, so
+/// delays of up to 2 cycles return straight away, after only that fixed
+/// overhead.
+///
+/// The body is not padded with `nop`s. `asm!` is never optimized away, so
+/// padding is not needed to keep the loop, and it would only make each
+/// iteration longer and the delay coarser.
+///
+/// The counts hold for the default core configuration (`ENABLE_REGS_DUALPORT`
+/// on, neither `TWO_CYCLE_ALU` nor `TWO_CYCLE_COMPARE`) running from memory
+/// without wait states. Other configurations, slower instruction memory and
+/// interrupts taken during the loop all make the delay longer.
+#[inline]
+#[allow(unused_variables)]
+pub fn delay_cycles(cycles: u32) {
+    // Round to the nearest `8 * k - 2`, without overflowing on large counts
+    let iterations =
+        cycles / DELAY_LOOP_CYCLES + u32::from(cycles % DELAY_LOOP_CYCLES >= 3);
+
+    if iterations == 0 {
+        return;
+    }
+
+    match () {
+        #[cfg(riscv)]
+        () => unsafe {
+            asm!(
+                "1:",
+                "addi {0}, {0}, -1",
+                "bnez {0}, 1b",
+                inout(reg) iterations => _,
+                options(nomem, nostack),
+                );
+        },
+
+        // Rounding up can take `8 * k` one past `u32::MAX`
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::advance(iterations.saturating_mul(DELAY_LOOP_CYCLES) - 2),
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
+        () => unimplemented!(),
+    }
+}
+
+/// `rdcycle` instruction wrapper
+///
+/// Returns the low 32 bits of the cycle counter.
+///
+/// Note: this function is only available when the `counters` feature is
+/// enabled.
+///
+/// # Safety
+///
+/// - The core must be built with `ENABLE_COUNTERS`. Otherwise the instruction
+///   is illegal and raises the trap on IRQ line 1, or halts the core if that
+///   line can't be taken
+#[inline]
+#[cfg(feature = "counters")]
+pub unsafe fn rdcycle() -> u32 {
+    match () {
+        #[cfg(riscv)]
+        () => {
+            let ret: u32;
+
+            asm!("rdcycle {0}", out(reg) ret);
+
+            ret
+        }
+
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => crate::mock::cycles() as u32,
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
+        () => unimplemented!(),
+    }
+}
+
+/// `rdcycleh` instruction wrapper
+///
+/// Returns the high 32 bits of the cycle counter. RV64 has no `rdcycleh`, so
+/// there the high half is taken from a full width `rdcycle`.
+///
+/// Note: this function is only available when the `counters` feature is
+/// enabled.
+///
+/// # Safety
+///
+/// - The core must be built with `ENABLE_COUNTERS` and `ENABLE_COUNTERS64`.
+///   Otherwise the instruction is illegal and raises the trap on IRQ line 1,
+///   or halts the core if that line can't be taken
+#[inline]
+#[cfg(feature = "counters")]
+pub unsafe fn rdcycleh() -> u32 {
+    match () {
+        #[cfg(riscv32)]
+        () => {
+            let ret: u32;
+
+            asm!("rdcycleh {0}", out(reg) ret);
+
+            ret
+        }
+
+        #[cfg(riscv64)]
+        () => {
+            let ret: u64;
+
+            asm!("rdcycle {0}", out(reg) ret);
+
+            (ret >> 32) as u32
+        }
+
+        #[cfg(all(not(riscv), feature = "mock-host"))]
+        () => (crate::mock::cycles() >> 32) as u32,
+
+        #[cfg(all(not(riscv), not(feature = "mock-host")))]
         () => unimplemented!(),
     }
 }
+
+/// Returns the full 64 bit cycle counter
+///
+/// On RV32 the two halves can't be read at once, so the high half is read
+/// before and after the low half and the read is retried if it changed in
+/// between. On RV64 the counter is read in one go.
+///
+/// Note: this function is only available when the `counters` feature is
+/// enabled.
+///
+/// # Safety
+///
+/// - The requirements of `rdcycleh` apply
+#[inline]
+#[cfg(feature = "counters")]
+pub unsafe fn cycles64() -> u64 {
+    match () {
+        #[cfg(riscv64)]
+        () => {
+            let ret: u64;
+
+            asm!("rdcycle {0}", out(reg) ret);
+
+            ret
+        }
+
+        #[cfg(not(riscv64))]
+        () => loop {
+            let hi = rdcycleh();
+            let lo = rdcycle();
+
+            if rdcycleh() == hi {
+                return (u64::from(hi) << 32) | u64::from(lo);
+            }
+        },
+    }
+}
+
+/// Runs `f` and returns its result with the number of cycles it took
+///
+/// The cycle counter is read before and after `f`, with compiler fences so
+/// that the work of `f` can't be moved outside the measurement. The count
+/// is the wrapping difference of the two reads, so the counter wrapping during
+/// `f` is handled. Measurements longer than `u32::MAX` cycles wrap.
+///
+/// ```ignore
+/// let (sum, cycles) = unsafe { asm::measure(|| checksum(&buf)) };
+/// ```
+///
+/// Note: this function is only available when the `counters` feature is
+/// enabled.
+///
+/// # Safety
+///
+/// - The requirements of `rdcycle` apply
+#[inline(always)]
+#[cfg(feature = "counters")]
+pub unsafe fn measure<F, R>(f: F) -> (R, u32)
+where
+    F: FnOnce() -> R,
+{
+    let start = rdcycle();
+    compiler_fence(Ordering::SeqCst);
+
+    let r = f();
+
+    compiler_fence(Ordering::SeqCst);
+    let end = rdcycle();
+
+    (r, end.wrapping_sub(start))
+}
+
+/// The RISC-V `CUSTOM_0` major opcode, used by the PicoRV32 interrupt
+/// instructions unless another one was selected (see `OPCODE`)
+///
+/// See [Custom Instructions for IRQ Handling](https://github.com/YosysHQ/picorv32#custom-instructions-for-irq-handling)
+/// in the PicoRV32 manual.
+pub const OPCODE_CUSTOM_0: u32 = 0b0001011;
+
+/// `func7` of `getq rd, qs`
+///
+/// See [getq rd, qs](https://github.com/YosysHQ/picorv32#getq-rd-qs) in the
+/// PicoRV32 manual.
+pub const FUNC7_GETQ: u32 = 0b0000000;
+
+/// `func7` of `setq qd, rs`
+///
+/// See [setq qd, rs](https://github.com/YosysHQ/picorv32#setq-qd-rs) in the
+/// PicoRV32 manual.
+pub const FUNC7_SETQ: u32 = 0b0000001;
+
+/// `func7` of `retirq`
+///
+/// See [retirq](https://github.com/YosysHQ/picorv32#retirq) in the PicoRV32
+/// manual.
+pub const FUNC7_RETIRQ: u32 = 0b0000010;
+
+/// `func7` of `maskirq rd, rs`
+///
+/// See [maskirq](https://github.com/YosysHQ/picorv32#maskirq) in the
+/// PicoRV32 manual.
+pub const FUNC7_MASKIRQ: u32 = 0b0000011;
+
+/// `func7` of `waitirq rd`
+///
+/// See [waitirq](https://github.com/YosysHQ/picorv32#waitirq) in the
+/// PicoRV32 manual.
+pub const FUNC7_WAITIRQ: u32 = 0b0000100;
+
+/// `func7` of `timer rd, rs`
+///
+/// See [timer](https://github.com/YosysHQ/picorv32#timer) in the PicoRV32
+/// manual.
+pub const FUNC7_TIMER: u32 = 0b0000101;
+
+/// Opcode used by all the interrupt instructions
+///
+/// This is `CUSTOM_0` (`0b0001011`) unless the crate was built with
+/// `COUCAL_CUSTOM_OPCODE` set to select another custom opcode, see the crate
+/// documentation.
+#[cfg(not(any(
+    coucal_opcode = "custom1",
+    coucal_opcode = "custom2",
+    coucal_opcode = "custom3"
+)))]
+pub const OPCODE: u32 = OPCODE_CUSTOM_0;
+
+/// Opcode used by all the interrupt instructions (`CUSTOM_1`, selected with
+/// `COUCAL_CUSTOM_OPCODE`)
+#[cfg(coucal_opcode = "custom1")]
+pub const OPCODE: u32 = 0b0101011;
+
+/// Opcode used by all the interrupt instructions (`CUSTOM_2`, selected with
+/// `COUCAL_CUSTOM_OPCODE`)
+#[cfg(coucal_opcode = "custom2")]
+pub const OPCODE: u32 = 0b1011011;
+
+/// Opcode used by all the interrupt instructions (`CUSTOM_3`, selected with
+/// `COUCAL_CUSTOM_OPCODE`)
+#[cfg(coucal_opcode = "custom3")]
+pub const OPCODE: u32 = 0b1111011;
+
+/// A PicoRV32 interrupt instruction
+///
+/// Each variant carries the register operands of the instruction. Integer
+/// registers are given by their number (`rd = 10` is `x10`) and q-registers
+/// by their offset (`qs = 2` is `q2`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// `getq rd, qs`
+    Getq { rd: u8, qs: u8 },
+    /// `setq qd, rs`
+    Setq { qd: u8, rs: u8 },
+    /// `retirq`
+    Retirq,
+    /// `maskirq rd, rs`
+    Maskirq { rd: u8, rs: u8 },
+    /// `waitirq rd`
+    Waitirq { rd: u8 },
+    /// `timer rd, rs`
+    Timer { rd: u8, rs: u8 },
+}
+
+impl Instruction {
+    /// Returns the machine word for the instruction
+    ///
+    /// The instruction is encoded in the R format with `func3` and `rs2` set
+    /// to zero, exactly as the wrappers in this module emit it. Register
+    /// operands are truncated to 5 bits.
+    pub fn encode(&self) -> u32 {
+        let (func7, rd, rs1) = match *self {
+            Instruction::Getq { rd, qs } => (FUNC7_GETQ, rd, qs),
+            Instruction::Setq { qd, rs } => (FUNC7_SETQ, qd, rs),
+            Instruction::Retirq => (FUNC7_RETIRQ, 0, 0),
+            Instruction::Maskirq { rd, rs } => (FUNC7_MASKIRQ, rd, rs),
+            Instruction::Waitirq { rd } => (FUNC7_WAITIRQ, rd, 0),
+            Instruction::Timer { rd, rs } => (FUNC7_TIMER, rd, rs),
+        };
+
+        (func7 << 25)
+            | ((u32::from(rs1) & 0x1f) << 15)
+            | ((u32::from(rd) & 0x1f) << 7)
+            | OPCODE
+    }
+
+    /// Decodes a machine word into an instruction
+    ///
+    /// Returns `None` if `word` is not one of the PicoRV32 interrupt
+    /// instructions. Like the core itself, this ignores the `func3` and `rs2`
+    /// fields, along with any register field the instruction does not use.
+    pub fn decode(word: u32) -> Option<Instruction> {
+        if word & 0x7f != OPCODE {
+            return None;
+        }
+
+        let rd = ((word >> 7) & 0x1f) as u8;
+        let rs1 = ((word >> 15) & 0x1f) as u8;
+
+        match word >> 25 {
+            FUNC7_GETQ => Some(Instruction::Getq { rd, qs: rs1 }),
+            FUNC7_SETQ => Some(Instruction::Setq { qd: rd, rs: rs1 }),
+            FUNC7_RETIRQ => Some(Instruction::Retirq),
+            FUNC7_MASKIRQ => Some(Instruction::Maskirq { rd, rs: rs1 }),
+            FUNC7_WAITIRQ => Some(Instruction::Waitirq { rd }),
+            FUNC7_TIMER => Some(Instruction::Timer { rd, rs: rs1 }),
+            _ => None,
+        }
+    }
+
+    /// Executes the instruction using the wrappers in this module
+    ///
+    /// The integer register operands are not used: the wrappers leave
+    /// register allocation to the compiler. Instead `value` is passed in the
+    /// source register and the value written to the destination register is
+    /// returned. Instructions without a destination register return zero.
+    ///
+    /// # Panics
+    ///
+    /// `Getq` and `Setq` panic if q-registers are not enabled or if the
+    /// q-register index is 4 or more. `Timer` panics if the timer is not
+    /// enabled.
+    ///
+    /// # Safety
+    ///
+    /// - The safety requirements of the wrapper being dispatched to apply
+    #[allow(unused_variables)]
+    pub unsafe fn execute(&self, value: u32) -> u32 {
+        match *self {
+            Instruction::Getq { qs, .. } => match qs {
+                #[cfg(feature = "interrupts-qregs")]
+                0 => getq::<0>(),
+                #[cfg(feature = "interrupts-qregs")]
+                1 => getq::<1>(),
+                #[cfg(feature = "interrupts-qregs")]
+                2 => getq::<2>(),
+                #[cfg(feature = "interrupts-qregs")]
+                3 => getq::<3>(),
+                _ => panic!("no getq wrapper for q{}", qs),
+            },
+            Instruction::Setq { qd, .. } => match qd {
+                #[cfg(feature = "interrupts-qregs")]
+                0 => {
+                    setq::<0>(value);
+
+                    0
+                }
+                #[cfg(feature = "interrupts-qregs")]
+                1 => {
+                    setq::<1>(value);
+
+                    0
+                }
+                #[cfg(feature = "interrupts-qregs")]
+                2 => {
+                    setq::<2>(value);
+
+                    0
+                }
+                #[cfg(feature = "interrupts-qregs")]
+                3 => {
+                    setq::<3>(value);
+
+                    0
+                }
+                _ => panic!("no setq wrapper for q{}", qd),
+            },
+            Instruction::Retirq => retirq(),
+            Instruction::Maskirq { .. } => maskirq(value),
+            Instruction::Waitirq { .. } => waitirq(),
+            #[cfg(feature = "timer")]
+            Instruction::Timer { .. } => timer(value),
+            #[cfg(not(feature = "timer"))]
+            Instruction::Timer { .. } => panic!("the timer is not enabled"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Formats the instruction in PicoRV32 assembly syntax with ABI register
+    /// names, as objdump prints it, for example `setq q2, t0` or
+    /// `waitirq a0`
+    ///
+    /// The alternate form (`{:#}`) uses numeric register names instead, for
+    /// example `setq q2, x5` or `waitirq x10`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let x = |n| Register {
+            n,
+            numeric: f.alternate(),
+        };
+
+        match *self {
+            Instruction::Getq { rd, qs } => write!(f, "getq {}, q{}", x(rd), qs),
+            Instruction::Setq { qd, rs } => write!(f, "setq q{}, {}", qd, x(rs)),
+            Instruction::Retirq => write!(f, "retirq"),
+            Instruction::Maskirq { rd, rs } => write!(f, "maskirq {}, {}", x(rd), x(rs)),
+            Instruction::Waitirq { rd } => write!(f, "waitirq {}", x(rd)),
+            Instruction::Timer { rd, rs } => write!(f, "timer {}, {}", x(rd), x(rs)),
+        }
+    }
+}
+
+/// An integer register operand of `Instruction`, for formatting
+struct Register {
+    n: u8,
+    numeric: bool,
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const ABI_NAMES: [&str; 32] = [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3",
+            "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+            "t3", "t4", "t5", "t6",
+        ];
+
+        if self.numeric {
+            write!(f, "x{}", self.n)
+        } else {
+            f.write_str(ABI_NAMES[usize::from(self.n & 0x1f)])
+        }
+    }
+}
+
+/// Writes the assembly text for the machine word `word` to `out`
+///
+/// PicoRV32 interrupt instructions are rendered by the `Display`
+/// implementation of `Instruction`. Any other word is written as a `.word`
+/// directive, for example `.word 0x00000013`.
+pub fn disassemble<W: fmt::Write>(word: u32, out: &mut W) -> fmt::Result {
+    match Instruction::decode(word) {
+        Some(instruction) => write!(out, "{}", instruction),
+        None => write!(out, ".word {:#010x}", word),
+    }
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    const ALL: [Instruction; 6] = [
+        Instruction::Getq { rd: 10, qs: 2 },
+        Instruction::Setq { qd: 3, rs: 11 },
+        Instruction::Retirq,
+        Instruction::Maskirq { rd: 5, rs: 31 },
+        Instruction::Waitirq { rd: 1 },
+        Instruction::Timer { rd: 0, rs: 12 },
+    ];
+
+    #[test]
+    fn every_instruction_round_trips() {
+        for instruction in ALL.iter() {
+            assert_eq!(Instruction::decode(instruction.encode()), Some(*instruction));
+        }
+    }
+
+    #[test]
+    fn encode_uses_the_r_format() {
+        let word = Instruction::Maskirq { rd: 10, rs: 11 }.encode();
+
+        assert_eq!(word, (FUNC7_MASKIRQ << 25) | (11 << 15) | (10 << 7) | OPCODE);
+    }
+
+    #[test]
+    fn decode_rejects_other_instructions() {
+        // addi x0, x0, 0
+        assert_eq!(Instruction::decode(0x0000_0013), None);
+        assert_eq!(Instruction::decode((6 << 25) | OPCODE), None);
+    }
+
+    #[cfg(feature = "interrupts-qregs")]
+    #[test]
+    fn execute_reaches_every_q_register() {
+        mock::reset();
+
+        for qd in 0..4 {
+            unsafe {
+                Instruction::Setq { qd, rs: 0 }.execute(0x100 + u32::from(qd));
+            }
+        }
+
+        for qs in 0..4 {
+            let value = unsafe { Instruction::Getq { rd: 0, qs }.execute(0) };
+
+            assert_eq!(value, 0x100 + u32::from(qs));
+        }
+    }
+
+    #[test]
+    fn mask_scope_restores_the_old_mask_on_drop() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b1);
+
+            let outer = mask_scope(0b11);
+            assert_eq!(outer.old_mask(), 0b1);
+
+            {
+                let inner = mask_scope(0b111);
+                assert_eq!(inner.old_mask(), 0b11);
+                assert_eq!(mock::mask(), 0b111);
+            }
+
+            assert_eq!(mock::mask(), 0b11);
+
+            drop(outer);
+        }
+
+        assert_eq!(mock::mask(), 0b1);
+    }
+
+    #[test]
+    fn mask_or_sets_bits_and_returns_the_old_mask() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b0001);
+        }
+
+        assert_eq!(mask_or(0b0110), 0b0001);
+        assert_eq!(mock::mask(), 0b0111);
+        assert_eq!(mask_or(0), 0b0111);
+        assert_eq!(mock::mask(), 0b0111);
+    }
+
+    #[test]
+    fn mask_and_clears_bits_and_returns_the_old_mask() {
+        mock::reset();
+
+        unsafe {
+            assert_eq!(mask_and(!0b0101), 0xffff_ffff);
+            assert_eq!(mock::mask(), 0xffff_fffa);
+            assert_eq!(mask_and(0xffff_ffff), 0xffff_fffa);
+        }
+
+        assert_eq!(mock::mask(), 0xffff_fffa);
+    }
+
+    #[test]
+    fn disassemble_renders_the_wrapper_words() {
+        use std::string::String;
+
+        // With `CUSTOM_0` the first word is 0x0000850b
+        let words = [
+            (0x0000_8500 | OPCODE, "getq a0, q1"),
+            (0x0202_8100 | OPCODE, "setq q2, t0"),
+            (0x0400_0000 | OPCODE, "retirq"),
+            (0x0605_8500 | OPCODE, "maskirq a0, a1"),
+            (0x0800_0500 | OPCODE, "waitirq a0"),
+            (0x0a06_0000 | OPCODE, "timer zero, a2"),
+            // addi x0, x0, 0
+            (0x0000_0013, ".word 0x00000013"),
+        ];
+
+        for &(word, text) in words.iter() {
+            let mut out = String::new();
+            disassemble(word, &mut out).unwrap();
+
+            assert_eq!(out, text);
+        }
+    }
+
+    #[test]
+    fn alternate_display_uses_numeric_registers() {
+        let instruction = Instruction::Setq { qd: 2, rs: 5 };
+
+        assert_eq!(std::format!("{}", instruction), "setq q2, t0");
+        assert_eq!(std::format!("{:#}", instruction), "setq q2, x5");
+        assert_eq!(
+            std::format!("{:#}", Instruction::Waitirq { rd: 10 }),
+            "waitirq x10"
+        );
+    }
+
+    #[cfg(feature = "timer")]
+    #[test]
+    fn delay_cycles_advances_the_timer_by_the_loop_length() {
+        mock::reset();
+
+        unsafe {
+            timer(100);
+        }
+        let start = mock::counter();
+
+        // 3 iterations of 8 cycles, minus the untaken branch
+        delay_cycles(21);
+        assert_eq!(mock::counter(), start - 22);
+
+        // Up to 2 cycles, no iterations at all
+        delay_cycles(2);
+        assert_eq!(mock::counter(), start - 22);
+
+        delay_cycles(u32::MAX);
+        assert_eq!(mock::counter(), 0);
+        assert_eq!(mock::pending(), 0b1);
+    }
+}
@@ -0,0 +1,293 @@
+//! Atomic integers for cores without the A extension
+//!
+//! PicoRV32 has no atomic instructions, so `core::sync::atomic` is missing
+//! the read-modify-write operations on this target. The types in this module
+//! follow the `core` API and implement each operation by masking every
+//! interrupt around a plain access. On a single hart that makes each
+//! operation atomic with respect to interrupt handlers, and gives all of them
+//! a total order, so the `Ordering` arguments are accepted and ignored.
+//!
+//! The operations mask interrupts directly rather than through
+//! `interrupt::free`, so they can be used in any context, including while
+//! holding a lock checked by `lock_order` and inside a logger.
+//!
+//! Note: this module is only available when the `atomic-polyfill` feature
+//! is enabled.
+
+use crate::asm::maskirq;
+use core::cell::UnsafeCell;
+pub use core::sync::atomic::Ordering;
+
+/// Runs `f` with every interrupt masked
+#[inline]
+fn masked<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let old_mask = unsafe { maskirq(0xffff_ffff) };
+    let r = f();
+
+    unsafe {
+        maskirq(old_mask);
+    }
+
+    r
+}
+
+macro_rules! atomic_common {
+    ($atomic:ident, $t:ty) => {
+        impl $atomic {
+            /// Creates a new atomic value
+            pub const fn new(v: $t) -> Self {
+                $atomic {
+                    v: UnsafeCell::new(v),
+                }
+            }
+
+            /// Returns a mutable reference to the value
+            pub fn get_mut(&mut self) -> &mut $t {
+                self.v.get_mut()
+            }
+
+            /// Consumes the atomic and returns the value
+            pub fn into_inner(self) -> $t {
+                self.v.into_inner()
+            }
+
+            /// Loads the value
+            pub fn load(&self, _order: Ordering) -> $t {
+                masked(|| unsafe { *self.v.get() })
+            }
+
+            /// Stores `val`
+            pub fn store(&self, val: $t, _order: Ordering) {
+                masked(|| unsafe { *self.v.get() = val })
+            }
+
+            /// Stores `val` and returns the previous value
+            pub fn swap(&self, val: $t, _order: Ordering) -> $t {
+                masked(|| unsafe { core::ptr::replace(self.v.get(), val) })
+            }
+
+            /// Stores `new` if the value is `current`
+            ///
+            /// Returns the previous value, as `Ok` if it was `current` and
+            /// `Err` otherwise.
+            pub fn compare_exchange(
+                &self,
+                current: $t,
+                new: $t,
+                _success: Ordering,
+                _failure: Ordering,
+            ) -> Result<$t, $t> {
+                masked(|| unsafe {
+                    let v = *self.v.get();
+
+                    if v == current {
+                        *self.v.get() = new;
+
+                        Ok(v)
+                    } else {
+                        Err(v)
+                    }
+                })
+            }
+
+            /// Same as `compare_exchange`, which never fails spuriously here
+            pub fn compare_exchange_weak(
+                &self,
+                current: $t,
+                new: $t,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$t, $t> {
+                self.compare_exchange(current, new, success, failure)
+            }
+
+            /// Replaces the value with `f(value)` while `f` returns `Some`
+            ///
+            /// Returns the previous value, as `Ok` if `f` returned `Some`
+            /// and `Err` otherwise. `f` runs with interrupts masked, so it is
+            /// only called once.
+            pub fn fetch_update<F>(
+                &self,
+                _set_order: Ordering,
+                _fetch_order: Ordering,
+                mut f: F,
+            ) -> Result<$t, $t>
+            where
+                F: FnMut($t) -> Option<$t>,
+            {
+                masked(|| unsafe {
+                    let v = *self.v.get();
+
+                    match f(v) {
+                        Some(new) => {
+                            *self.v.get() = new;
+
+                            Ok(v)
+                        }
+                        None => Err(v),
+                    }
+                })
+            }
+
+            /// Replaces the value with `op(value, val)` and returns the
+            /// previous value
+            fn fetch_op(&self, val: $t, op: fn($t, $t) -> $t) -> $t {
+                masked(|| unsafe {
+                    let v = *self.v.get();
+                    *self.v.get() = op(v, val);
+
+                    v
+                })
+            }
+
+            /// Bitwise and with `val`, returning the previous value
+            pub fn fetch_and(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, |a, b| a & b)
+            }
+
+            /// Bitwise nand with `val`, returning the previous value
+            pub fn fetch_nand(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, |a, b| !(a & b))
+            }
+
+            /// Bitwise or with `val`, returning the previous value
+            pub fn fetch_or(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, |a, b| a | b)
+            }
+
+            /// Bitwise xor with `val`, returning the previous value
+            pub fn fetch_xor(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, |a, b| a ^ b)
+            }
+        }
+
+        // Every access happens with interrupts masked on a single hart
+        unsafe impl Sync for $atomic {}
+
+        impl Default for $atomic {
+            fn default() -> Self {
+                $atomic::new(Default::default())
+            }
+        }
+
+        impl From<$t> for $atomic {
+            fn from(v: $t) -> Self {
+                $atomic::new(v)
+            }
+        }
+
+        impl core::fmt::Debug for $atomic {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                core::fmt::Debug::fmt(&self.load(Ordering::Relaxed), f)
+            }
+        }
+    };
+}
+
+macro_rules! atomic_int {
+    ($atomic:ident, $t:ty) => {
+        /// An integer that can be shared with interrupt handlers
+        #[repr(transparent)]
+        pub struct $atomic {
+            v: UnsafeCell<$t>,
+        }
+
+        atomic_common!($atomic, $t);
+
+        impl $atomic {
+            /// Wrapping add of `val`, returning the previous value
+            pub fn fetch_add(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, <$t>::wrapping_add)
+            }
+
+            /// Wrapping subtract of `val`, returning the previous value
+            pub fn fetch_sub(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, <$t>::wrapping_sub)
+            }
+
+            /// Maximum with `val`, returning the previous value
+            pub fn fetch_max(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, <$t>::max)
+            }
+
+            /// Minimum with `val`, returning the previous value
+            pub fn fetch_min(&self, val: $t, _order: Ordering) -> $t {
+                self.fetch_op(val, <$t>::min)
+            }
+        }
+    };
+}
+
+atomic_int!(AtomicU32, u32);
+atomic_int!(AtomicUsize, usize);
+
+/// A boolean that can be shared with interrupt handlers
+#[repr(transparent)]
+pub struct AtomicBool {
+    v: UnsafeCell<bool>,
+}
+
+atomic_common!(AtomicBool, bool);
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn fetch_add_and_fetch_sub_wrap() {
+        mock::reset();
+
+        let a = AtomicU32::new(u32::MAX);
+
+        assert_eq!(a.fetch_add(2, Ordering::SeqCst), u32::MAX);
+        assert_eq!(a.load(Ordering::SeqCst), 1);
+        assert_eq!(a.fetch_sub(2, Ordering::SeqCst), 1);
+        assert_eq!(a.load(Ordering::SeqCst), u32::MAX);
+    }
+
+    #[test]
+    fn compare_exchange_only_stores_on_a_match() {
+        mock::reset();
+
+        let a = AtomicUsize::new(5);
+
+        assert_eq!(
+            a.compare_exchange(5, 6, Ordering::SeqCst, Ordering::SeqCst),
+            Ok(5)
+        );
+        assert_eq!(
+            a.compare_exchange(5, 7, Ordering::SeqCst, Ordering::SeqCst),
+            Err(6)
+        );
+        assert_eq!(a.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn swap_returns_the_previous_value() {
+        mock::reset();
+
+        let a = AtomicBool::new(false);
+
+        assert!(!a.swap(true, Ordering::SeqCst));
+        assert!(a.swap(true, Ordering::SeqCst));
+        assert!(a.into_inner());
+    }
+
+    #[test]
+    fn operations_restore_the_mask() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b110);
+        }
+
+        let a = AtomicU32::new(0);
+        a.fetch_or(0b1, Ordering::SeqCst);
+
+        assert_eq!(mock::mask(), 0b110);
+    }
+}
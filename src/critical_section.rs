@@ -0,0 +1,64 @@
+//! `critical-section` implementation
+//!
+//! Enabling the `critical-section` feature registers this implementation
+//! with the `critical-section` crate. Only one implementation can be linked
+//! into a program, so no other crate in the dependency graph may provide one
+//! as well (for example through `critical-section/std`).
+//!
+//! The restore state is the saved IRQ mask, which is why the feature turns on
+//! `critical-section/restore-state-u32`: no other restore state size can be
+//! selected alongside it.
+
+use crate::asm::maskirq;
+use critical_section::{set_impl, Impl, RawRestoreState};
+
+struct PicoRv32CriticalSection;
+set_impl!(PicoRv32CriticalSection);
+
+unsafe impl Impl for PicoRv32CriticalSection {
+    unsafe fn acquire() -> RawRestoreState {
+        maskirq(0xffff_ffff)
+    }
+
+    unsafe fn release(old_mask: RawRestoreState) {
+        maskirq(old_mask);
+    }
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use crate::mock;
+
+    #[test]
+    fn nested_sections_restore_the_mask_they_found() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        unsafe { crate::asm::maskirq(0b1011) };
+
+        critical_section::with(|_| {
+            assert_eq!(mock::mask(), 0xffff_ffff);
+
+            critical_section::with(|_| assert_eq!(mock::mask(), 0xffff_ffff));
+            assert_eq!(mock::mask(), 0xffff_ffff);
+        });
+        assert_eq!(mock::mask(), 0b1011);
+    }
+
+    #[test]
+    fn acquire_and_release_round_trip_the_mask() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        unsafe { crate::asm::maskirq(0b0110) };
+
+        unsafe {
+            let outer = critical_section::acquire();
+            assert_eq!(mock::mask(), 0xffff_ffff);
+            let inner = critical_section::acquire();
+
+            critical_section::release(inner);
+            assert_eq!(mock::mask(), 0xffff_ffff);
+            critical_section::release(outer);
+        }
+        assert_eq!(mock::mask(), 0b0110);
+    }
+}
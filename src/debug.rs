@@ -0,0 +1,38 @@
+//! Debug output through semihosting
+//!
+//! For bring-up before any UART works, text can be printed on the console of
+//! a debugger that services RISC-V semihosting calls. Every call stops the
+//! core until the debugger has handled it, so this is far too slow for
+//! anything but debugging, and without a debugger attached each call raises
+//! the `ebreak` trap (see `asm::semihost_write`).
+//!
+//! Note: this module is only available when the `semihosting` feature is
+//! enabled.
+
+use crate::asm::semihost_write;
+
+/// Debugger stdout file descriptor
+const STDOUT: u32 = 1;
+
+/// Prints `s` on the debugger's stdout
+pub fn print_str(s: &str) {
+    unsafe {
+        semihost_write(STDOUT, s.as_ptr(), s.len());
+    }
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn print_str_writes_the_whole_string() {
+        mock::reset();
+
+        print_str("hello");
+        print_str(", world");
+
+        assert_eq!(mock::take_semihost_output(), b"hello, world");
+    }
+}
@@ -0,0 +1,203 @@
+//! `embedded-hal` trait implementations
+//!
+//! Note: this module is only available when the `embedded-hal` feature is
+//! enabled. The feature also enables `timer`.
+
+use crate::asm::timer;
+use crate::interrupt;
+use crate::timer::delay_long;
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use embedded_hal::timer::{CountDown, Periodic};
+use void::Void;
+
+/// A count down timer over the PicoRV32 timer, counting in clock cycles
+///
+/// `start` arms the timer with the given number of cycles. When the count
+/// reaches zero the core raises the timer interrupt (line 0). Whether that
+/// interrupt is masked or serviced is up to the application; `wait` does not
+/// depend on it.
+///
+/// The pending bit of the timer interrupt can only be observed by a handler
+/// (through `q1`), so `wait` detects expiry by reading the counter instead.
+/// The counter can only be read by writing to it, so each poll writes zero,
+/// which returns the count, and re-arms the timer with the count read back.
+/// This is done inside a critical section, and the counter is stopped for
+/// the few cycles between the two instructions.
+///
+/// The timer is periodic: when `wait` sees that the count has reached zero
+/// it re-arms the timer with the count passed to `start`. The cycles between
+/// the expiry and the poll that sees it are not made up for, so the periods
+/// drift by the polling latency.
+///
+/// There is only one timer, so only one `Timer` should be used at a time.
+#[derive(Debug)]
+pub struct Timer {
+    period: u32,
+}
+
+impl Timer {
+    /// Creates a timer without arming it
+    pub const fn new() -> Self {
+        Timer { period: 0 }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl CountDown for Timer {
+    type Time = u32;
+
+    fn start<T>(&mut self, count: T)
+    where
+        T: Into<u32>,
+    {
+        self.period = count.into();
+
+        unsafe {
+            timer(self.period);
+        }
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        interrupt::free(|_| unsafe {
+            let left = timer(0);
+
+            if left != 0 {
+                timer(left);
+
+                Err(nb::Error::WouldBlock)
+            } else {
+                timer(self.period);
+
+                Ok(())
+            }
+        })
+    }
+}
+
+impl Periodic for Timer {}
+
+/// A blocking delay over the PicoRV32 timer
+///
+/// Durations are converted to cycles of the core clock, rounding up, and
+/// waited for with `timer::delay_long`, which splits delays too long for the
+/// 32 bit counter. Like `delay_long` it masks every interrupt for the
+/// duration of the delay and discards any countdown that was running.
+///
+/// ```ignore
+/// let mut delay = Delay::new(48_000_000);
+///
+/// delay.delay_us(10u32);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Delay {
+    clock_hz: u32,
+}
+
+impl Delay {
+    /// Creates a delay for a core clocked at `clock_hz` Hz
+    pub const fn new(clock_hz: u32) -> Self {
+        Delay { clock_hz }
+    }
+
+    /// Returns the number of cycles in `count` periods of `1 / per_second`
+    /// seconds, rounded up
+    fn cycles(&self, count: u32, per_second: u64) -> u64 {
+        // Both factors fit in 32 bits, so the product can't overflow.
+        (u64::from(count) * u64::from(self.clock_hz)).div_ceil(per_second)
+    }
+}
+
+impl DelayUs<u32> for Delay {
+    fn delay_us(&mut self, us: u32) {
+        delay_long(self.cycles(us, 1_000_000));
+    }
+}
+
+impl DelayUs<u16> for Delay {
+    fn delay_us(&mut self, us: u16) {
+        self.delay_us(u32::from(us));
+    }
+}
+
+impl DelayUs<u8> for Delay {
+    fn delay_us(&mut self, us: u8) {
+        self.delay_us(u32::from(us));
+    }
+}
+
+impl DelayMs<u32> for Delay {
+    fn delay_ms(&mut self, ms: u32) {
+        delay_long(self.cycles(ms, 1_000));
+    }
+}
+
+impl DelayMs<u16> for Delay {
+    fn delay_ms(&mut self, ms: u16) {
+        self.delay_ms(u32::from(ms));
+    }
+}
+
+impl DelayMs<u8> for Delay {
+    fn delay_ms(&mut self, ms: u8) {
+        self.delay_ms(u32::from(ms));
+    }
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn delay_ms_waits_for_every_cycle_in_32_bit_chunks() {
+        mock::reset();
+        mock::set_poll_cycles(1 << 20);
+
+        unsafe {
+            crate::asm::maskirq(0b1000);
+        }
+
+        // 100 s at 48 MHz is 4.8e9 cycles, more than one countdown.
+        Delay::new(48_000_000).delay_ms(100_000u32);
+
+        assert_eq!(mock::expiries(), 2);
+        assert_eq!(mock::mask(), 0b1000);
+    }
+
+    #[test]
+    fn delay_us_converts_to_cycles() {
+        mock::reset();
+
+        Delay::new(48_000_000).delay_us(250u32);
+
+        #[cfg(feature = "counters")]
+        assert!(mock::cycles() >= 12_000);
+        assert_eq!(mock::expiries(), 1);
+
+        Delay::new(48_000_000).delay_us(0u8);
+
+        assert_eq!(mock::expiries(), 1);
+    }
+
+    #[test]
+    fn timer_wait_blocks_until_the_count_runs_out_then_rearms() {
+        mock::reset();
+
+        let mut timer = Timer::new();
+        timer.start(100u32);
+
+        let mut polls = 0;
+        while timer.wait().is_err() {
+            polls += 1;
+        }
+
+        assert!(polls > 0);
+        assert_eq!(mock::expiries(), 1);
+        assert_eq!(mock::counter(), 100);
+    }
+}
@@ -1,16 +1,130 @@
 //! Interrupts
 
+pub use self::controller::Controller;
+pub use self::mask::{maskirq_typed, IrqMask};
+#[cfg(feature = "stats")]
+pub use self::stats::{stats, Stats};
+#[cfg(feature = "async")]
+pub use self::wake::{wait_irq_async, wake, IrqFuture};
 use crate::asm::*;
-pub use bare_metal::{CriticalSection, Mutex, Nr};
+pub use bare_metal::{CriticalSection, Mutex};
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::convert::TryFrom;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+
+pub mod controller;
+pub mod lock_order;
+pub mod mask;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "async")]
+pub mod wake;
+
+/// Interrupt number
+///
+/// This used to be re-exported from `bare_metal`, which dropped it in 1.0.
+///
+/// # Safety
+///
+/// - `nr` must return the IRQ line the implementor stands for. Functions
+///   such as `enable_irq` unmask whatever line it returns
+pub unsafe trait Nr {
+    /// Returns the number associated with an interrupt
+    fn nr(&self) -> u8;
+}
+
+/// IRQ line of the timer interrupt
+pub const TIMER_IRQ: u8 = 0;
+
+/// IRQ line of the `ebreak`, `ecall` and illegal instruction trap
+pub const EBREAK_IRQ: u8 = 1;
+
+/// IRQ line of the bus error and misaligned access trap
+pub const BUS_ERROR_IRQ: u8 = 2;
+
+/// A PicoRV32 IRQ line
+///
+/// Lines 0 to 2 are built into the core. The rest are wired to external
+/// interrupt sources and should be written as `External(n)` with `n` from 3
+/// to 31, which is what `try_from` produces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqLine {
+    /// The timer interrupt (line 0)
+    Timer,
+    /// The `ebreak`, `ecall` and illegal instruction trap (line 1)
+    Ebreak,
+    /// The bus error and misaligned access trap (line 2)
+    BusError,
+    /// An external interrupt line
+    External(u8),
+}
+
+unsafe impl Nr for IrqLine {
+    fn nr(&self) -> u8 {
+        match *self {
+            IrqLine::Timer => TIMER_IRQ,
+            IrqLine::Ebreak => EBREAK_IRQ,
+            IrqLine::BusError => BUS_ERROR_IRQ,
+            IrqLine::External(nr) => nr,
+        }
+    }
+}
+
+impl TryFrom<u8> for IrqLine {
+    type Error = u8;
+
+    /// Returns the line numbered `nr`, or `nr` back if it is 32 or more
+    fn try_from(nr: u8) -> Result<Self, u8> {
+        match nr {
+            TIMER_IRQ => Ok(IrqLine::Timer),
+            EBREAK_IRQ => Ok(IrqLine::Ebreak),
+            BUS_ERROR_IRQ => Ok(IrqLine::BusError),
+            3..=31 => Ok(IrqLine::External(nr)),
+            _ => Err(nr),
+        }
+    }
+}
+
+impl TryFrom<u32> for IrqLine {
+    type Error = u32;
+
+    /// Returns the line numbered `nr`, or `nr` back if it is 32 or more
+    ///
+    /// This takes line numbers in the width of the mask, such as the result
+    /// of `trailing_zeros` on a pending bitmask.
+    fn try_from(nr: u32) -> Result<Self, u32> {
+        match u8::try_from(nr) {
+            Ok(line) => IrqLine::try_from(line).map_err(u32::from),
+            Err(_) => Err(nr),
+        }
+    }
+}
 
 /// Disables all interrupts
+///
+/// Returns the previous mask, so a save/restore pair can be written as
+/// `let prev = disable(); /* ... */ enable_restore(prev);`.
+///
+/// # Safety
+///
+/// - Restoring the returned mask can unmask interrupts, so only restore it
+///   where `enable` could be called
 #[inline]
-pub unsafe fn disable() {
-    maskirq(0xffff_ffff);
+pub unsafe fn disable() -> u32 {
+    maskirq(0xffff_ffff)
 }
 
 /// Enables all the interrupts
 ///
+/// This unmasks every line, including any that were masked on purpose before
+/// the matching `disable`. To end a region started by `disable`, prefer
+/// `enable_restore` with the mask `disable` returned.
+///
 /// # Safety
 ///
 /// - Do not call this function inside an `interrupt::free` critical section
@@ -19,17 +133,479 @@ pub unsafe fn enable() {
     maskirq(0);
 }
 
+/// Writes back the mask returned by an earlier `disable`
+///
+/// Unlike `enable`, lines that were masked before the `disable` stay masked.
+///
+/// ```ignore
+/// let prev = unsafe { interrupt::disable() };
+/// reprogram_dma();
+/// unsafe { interrupt::enable_restore(prev) };
+/// ```
+///
+/// # Safety
+///
+/// - Do not call this function inside an `interrupt::free` critical section
+///   that started after the matching `disable`
+/// - `mask` must be the value returned by the matching `disable`, and nested
+///   pairs must be restored in the reverse order
+#[inline]
+pub unsafe fn enable_restore(mask: u32) {
+    maskirq(mask);
+}
+
+/// Masks every interrupt and returns the previous mask
+///
+/// Together with `restore` this is a save/restore pair for regions that
+/// `free` can't wrap in a closure, such as a region that spans a function
+/// boundary or an FFI call:
+///
+/// ```ignore
+/// let saved = interrupt::disable_and_save();
+/// let r = start_transfer();
+/// if r.is_err() {
+///     unsafe { interrupt::restore(saved) };
+///     return r;
+/// }
+/// finish_transfer();
+/// unsafe { interrupt::restore(saved) };
+/// ```
+#[inline]
+pub fn disable_and_save() -> u32 {
+    unsafe { maskirq(0xffff_ffff) }
+}
+
+/// Writes back a mask saved by `disable_and_save`
+///
+/// # Safety
+///
+/// - `mask` must be the value returned by the matching `disable_and_save`.
+///   Any other value corrupts the mask of the enclosing code
+/// - Nested pairs must be restored in the reverse order they were saved
+#[inline]
+pub unsafe fn restore(mask: u32) {
+    maskirq(mask);
+}
+
+/// Returns the current interrupt mask, leaving it unchanged
+///
+/// A `0b1` at any offset means that line is masked. The value can already be
+/// stale when it is returned if a handler changes the mask, so outside a
+/// critical section it is only good for diagnostics.
+#[inline]
+pub fn current_mask() -> u32 {
+    unsafe { crate::asm::current_mask() }
+}
+
+/// Unmasks the single interrupt line `irq`
+///
+/// The other lines keep their current state. `irq.nr()` must be less than 32.
+///
+/// # Safety
+///
+/// - Do not call this function inside an `interrupt::free` critical section
+#[inline]
+pub unsafe fn enable_irq<I: Nr>(irq: I) {
+    debug_assert!(irq.nr() < 32, "IRQ number {} out of range", irq.nr());
+
+    // Masking everything first reads the mask without letting a handler
+    // change it before it is written back.
+    let old_mask = maskirq(0xffff_ffff);
+    maskirq(old_mask & !(1 << irq.nr()));
+}
+
+/// Masks the single interrupt line `irq`
+///
+/// The other lines keep their current state. `irq.nr()` must be less than 32.
+#[inline]
+pub fn disable_irq<I: Nr>(irq: I) {
+    debug_assert!(irq.nr() < 32, "IRQ number {} out of range", irq.nr());
+
+    unsafe {
+        let old_mask = maskirq(0xffff_ffff);
+        maskirq(old_mask | (1 << irq.nr()));
+    }
+}
+
+/// Returns the mask bits of the lines in `irqs`
+fn irq_bits<I: Nr>(irqs: &[I]) -> u32 {
+    irqs.iter().fold(0, |bits, irq| {
+        debug_assert!(irq.nr() < 32, "IRQ number {} out of range", irq.nr());
+
+        bits | (1 << irq.nr())
+    })
+}
+
+/// Masks every interrupt line in `irqs`
+///
+/// The bits of all the lines are combined first and written with a single
+/// read-modify-write of the mask. The other lines keep their current state.
+/// Each `nr()` must be less than 32.
+#[inline]
+pub fn mask_irqs<I: Nr>(irqs: &[I]) {
+    let bits = irq_bits(irqs);
+
+    unsafe {
+        let old_mask = maskirq(0xffff_ffff);
+        maskirq(old_mask | bits);
+    }
+}
+
+/// Unmasks every interrupt line in `irqs`
+///
+/// The bits of all the lines are combined first and written with a single
+/// read-modify-write of the mask. The other lines keep their current state.
+/// Each `nr()` must be less than 32.
+///
+/// # Safety
+///
+/// - Do not call this function inside an `interrupt::free` critical section
+#[inline]
+pub unsafe fn unmask_irqs<I: Nr>(irqs: &[I]) {
+    let bits = irq_bits(irqs);
+
+    let old_mask = maskirq(0xffff_ffff);
+    maskirq(old_mask & !bits);
+}
+
+/// Depth of the `disable_nested` regions and the mask to restore when the
+/// outermost one ends
+static NESTING: Mutex<Cell<(u32, u32)>> = Mutex::new(Cell::new((0, 0)));
+
+/// Disables all interrupts, counting nested calls
+///
+/// Each call must be paired with a later `enable_nested`. Only the outermost
+/// pair touches the mask for real: the first `disable_nested` saves the
+/// mask before masking everything, and the matching `enable_nested`
+/// restores it. Inner pairs only change the count, so a function that uses
+/// the pair can be called from inside another such region without
+/// re-enabling interrupts early, as a raw `disable`/`enable` pair would.
+///
+/// `free` does not use the count. A `free` block inside a nested region
+/// restores the masked state it found, and a nested region inside a `free`
+/// block saves and restores the all-masked mask of the critical section.
+pub fn disable_nested() {
+    unsafe {
+        let old_mask = maskirq(0xffff_ffff);
+        let cs = CriticalSection::new();
+        let nesting = NESTING.borrow(cs);
+        let (depth, saved) = nesting.get();
+
+        if depth == 0 {
+            nesting.set((1, old_mask));
+        } else {
+            nesting.set((depth + 1, saved));
+        }
+    }
+}
+
+/// Ends a region started by `disable_nested`
+///
+/// Interrupts stay masked until the outermost region ends, at which point
+/// the mask saved by the outermost `disable_nested` is restored.
+///
+/// # Safety
+///
+/// - Each call must match an earlier `disable_nested` call
+/// - Do not end the outermost region inside an `interrupt::free` critical
+///   section that started after it, as restoring the mask would unmask
+///   interrupts inside that critical section
+pub unsafe fn enable_nested() {
+    let old_mask = maskirq(0xffff_ffff);
+    let cs = CriticalSection::new();
+    let nesting = NESTING.borrow(cs);
+    let (depth, saved) = nesting.get();
+
+    debug_assert!(depth > 0, "enable_nested without disable_nested");
+
+    match depth {
+        // Unpaired call, leave the mask as it was
+        0 => {
+            maskirq(old_mask);
+        }
+        1 => {
+            nesting.set((0, 0));
+            maskirq(saved);
+        }
+        _ => nesting.set((depth - 1, saved)),
+    }
+}
+
+/// Raises the `ebreak` trap and never returns
+///
+/// This is meant for panic handlers: the trap handler on IRQ line 1 sees the
+/// failure instead of the core spinning silently. The `ebreak` runs in a
+/// loop, so returning from the trap handler raises it again. See
+/// `asm::ebreak` for what happens on cores that don't turn `ebreak` into an
+/// interrupt.
+///
+/// ```ignore
+/// #[panic_handler]
+/// fn panic(_info: &PanicInfo) -> ! {
+///     interrupt::abort()
+/// }
+/// ```
+pub fn abort() -> ! {
+    loop {
+        unsafe {
+            ebreak();
+        }
+    }
+}
+
 /// Execute closure `f` in an interrupt-free context.
 ///
 /// This as also known as a "critical section".
+///
+/// `f` is handed the `CriticalSection` token by value. Its lifetime ends with
+/// the call, so references borrowed from a `Mutex` through it can't escape
+/// the closure.
+///
+/// With the `log` feature enabled, entering and leaving the critical section
+/// emit `log::trace!` entries carrying a sequence number and the interrupt
+/// mask. Both entries are logged with the caller's mask in place, never with
+/// interrupts masked by this function.
+///
+/// In debug builds the critical section takes part in the lock ordering
+/// checks of the `lock_order` module. With the `trace` feature enabled,
+/// entering and leaving it are reported to the `trace` sink.
+///
+/// # Calling `free` with everything masked
+///
+/// The mask can only be read by writing it, so `free` always starts with one
+/// `maskirq`. If every line was already masked, for example in a nested
+/// `free`, there is nothing to restore and the second `maskirq` is skipped;
+/// the mask stays all ones throughout.
+///
+/// Note that the core does not change the mask when it takes an interrupt.
+/// It just doesn't take another one until `retirq`. So inside a handler the
+/// mask is whatever the interrupted code had, and `free` masks and restores
+/// it as usual. Either way the mask `free` leaves behind is the one it found.
+///
+/// # Re-enabling interrupts
+///
+/// Every function in this crate that can unmask an interrupt while `f` runs
+/// is `unsafe` (`enable`, `with_boosted`, the raw `asm::maskirq`) or does
+/// nothing while all interrupts are masked (`poll_and_service`). Safe code
+/// inside `f` therefore cannot break the critical section through this
+/// crate: doing so is a compile error unless it is wrapped in `unsafe`.
+/// Functions added to the crate keep to this rule.
+///
+/// ```compile_fail,E0133
+/// coucal_rs::interrupt::free(|_| coucal_rs::interrupt::enable());
+/// ```
+///
+/// ```compile_fail,E0133
+/// use coucal_rs::interrupt::{self, IrqLine};
+///
+/// interrupt::free(|_| interrupt::enable_irq(IrqLine::Timer));
+/// ```
+///
+/// ```compile_fail,E0133
+/// use coucal_rs::interrupt::{self, IrqLine};
+///
+/// interrupt::free(|_| interrupt::with_irq_enabled(IrqLine::Timer, || ()));
+/// ```
+///
+/// ```compile_fail,E0133
+/// coucal_rs::interrupt::free(|_| coucal_rs::asm::mask_and(0));
+/// ```
 pub fn free<F, R>(f: F) -> R
 where
-    F: FnOnce(&CriticalSection) -> R,
+    F: FnOnce(CriticalSection) -> R,
 {
+    #[cfg(feature = "log")]
+    let sequence = log_enter();
+
+    lock_order::acquire(lock_order::INTERRUPT_LEVEL);
+
     // disable interrupts
     let old_mask = unsafe { maskirq(0xffff_ffff) };
 
-    let r = f(unsafe { &CriticalSection::new() });
+    let cs = unsafe { CriticalSection::new() };
+
+    #[cfg(feature = "trace")]
+    trace::record_in(cs, trace::TraceEvent::CriticalEnter);
+
+    let r = f(cs);
+
+    #[cfg(feature = "trace")]
+    trace::record_in(cs, trace::TraceEvent::CriticalExit);
+
+    if old_mask != 0xffff_ffff {
+        unsafe {
+            maskirq(old_mask);
+        }
+    }
+
+    lock_order::release(lock_order::INTERRUPT_LEVEL);
+
+    #[cfg(feature = "log")]
+    log::trace!(
+        "critical section {} exit, mask {:#010x}",
+        sequence,
+        old_mask
+    );
+
+    r
+}
+
+#[cfg(feature = "log")]
+static FREE_SEQUENCE: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Allocates the next critical section sequence number and logs the entry
+#[cfg(feature = "log")]
+fn log_enter() -> u32 {
+    // This can't use `free` itself. The mask is only held for the increment
+    // so the entry is logged with the caller's mask in place.
+    let (sequence, mask) = unsafe {
+        let mask = maskirq(0xffff_ffff);
+        let cs = CriticalSection::new();
+        let sequence = FREE_SEQUENCE.borrow(cs).get().wrapping_add(1);
+        FREE_SEQUENCE.borrow(cs).set(sequence);
+        maskirq(mask);
+
+        (sequence, mask)
+    };
+
+    log::trace!("critical section {} enter, mask {:#010x}", sequence, mask);
+
+    sequence
+}
+
+/// A critical section held until this guard is dropped
+///
+/// Returned by `enter`. Dropping the guard restores the mask that was in
+/// place when it was created, so the critical section also ends on an early
+/// return. It takes part in logging, lock ordering and tracing exactly like
+/// `free`.
+#[must_use = "the critical section ends as soon as the guard is dropped"]
+pub struct CriticalGuard {
+    old_mask: u32,
+    #[cfg(feature = "log")]
+    sequence: u32,
+    // The guard restores the mask of the context that created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl CriticalGuard {
+    /// Returns the critical section token, e.g. to borrow a `Mutex`
+    ///
+    /// The token borrows the guard, so it can't outlive the critical section.
+    pub fn cs(&self) -> CriticalSection<'_> {
+        unsafe { CriticalSection::new() }
+    }
+}
+
+impl Drop for CriticalGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "trace")]
+        trace::record_in(self.cs(), trace::TraceEvent::CriticalExit);
+
+        if self.old_mask != 0xffff_ffff {
+            unsafe {
+                maskirq(self.old_mask);
+            }
+        }
+
+        lock_order::release(lock_order::INTERRUPT_LEVEL);
+
+        #[cfg(feature = "log")]
+        log::trace!(
+            "critical section {} exit, mask {:#010x}",
+            self.sequence,
+            self.old_mask
+        );
+    }
+}
+
+/// Enters a critical section that lasts until the returned guard is dropped
+///
+/// This is the statement form of `free`:
+///
+/// ```ignore
+/// fn read_config() -> Result<u32, Error> {
+///     let guard = unsafe { interrupt::enter() };
+///     let config = CONFIG.borrow(guard.cs()).get();
+///     check(config)?; // the guard restores the mask here too
+///     Ok(config)
+/// }
+/// ```
+///
+/// # Safety
+///
+/// - Nested guards must be dropped in the reverse order they were created.
+///   Dropping an outer guard first restores a mask that can unmask
+///   interrupts while the inner guard's critical section is still in use
+pub unsafe fn enter() -> CriticalGuard {
+    #[cfg(feature = "log")]
+    let sequence = log_enter();
+
+    lock_order::acquire(lock_order::INTERRUPT_LEVEL);
+
+    let old_mask = maskirq(0xffff_ffff);
+
+    #[cfg(feature = "trace")]
+    trace::record_in(CriticalSection::new(), trace::TraceEvent::CriticalEnter);
+
+    CriticalGuard {
+        old_mask,
+        #[cfg(feature = "log")]
+        sequence,
+        _not_send: PhantomData,
+    }
+}
+
+/// A critical section that some interrupt lines can still break into
+///
+/// Handed out by `free_except`. It is deliberately not a `CriticalSection`,
+/// so it can't be used to borrow a `Mutex`: the handlers of the kept lines
+/// can run at any point while it is held.
+pub struct PartialCriticalSection {
+    keep: u32,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PartialCriticalSection {
+    /// Returns the lines that were left unmasked, as passed to `free_except`
+    pub fn keep(&self) -> u32 {
+        self.keep
+    }
+}
+
+/// Execute closure `f` with every interrupt masked except the lines in `keep`
+///
+/// A `0b1` in `keep` selects a line. The kept lines are left as they were in
+/// the caller's mask, so a line that was already masked stays masked, and
+/// every other line is masked. The previous mask is restored when `f`
+/// returns.
+///
+/// This is for sections that must not delay a time-critical line, such as a
+/// motor control timer. **It is not a critical section with respect to the
+/// kept lines**: their handlers can interrupt `f` at any instruction, so any
+/// data that `f` shares with those handlers is not protected. That is why `f`
+/// is given a `PartialCriticalSection` rather than a `CriticalSection`.
+///
+/// ```ignore
+/// // The commutation timer must keep running while we update the display
+/// interrupt::free_except(1 << TIMER_IRQ, |_| {
+///     display.write_frame(&FRAME);
+/// });
+/// ```
+pub fn free_except<F, R>(keep: u32, f: F) -> R
+where
+    F: FnOnce(&PartialCriticalSection) -> R,
+{
+    let old_mask = unsafe { maskirq(0xffff_ffff) };
+
+    unsafe {
+        maskirq(old_mask | !keep);
+    }
+
+    let r = f(&PartialCriticalSection {
+        keep,
+        _not_send: PhantomData,
+    });
 
     unsafe {
         maskirq(old_mask);
@@ -37,3 +613,2558 @@ where
 
     r
 }
+
+/// Maximum number of work items that can be waiting in the deferred queue
+pub const DEFERRED_CAPACITY: usize = 16;
+
+struct DeferredQueue {
+    work: [Option<fn()>; DEFERRED_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+static DEFERRED: Mutex<RefCell<DeferredQueue>> = Mutex::new(RefCell::new(DeferredQueue {
+    work: [None; DEFERRED_CAPACITY],
+    head: 0,
+    len: 0,
+}));
+
+/// Queues `work` to be run later by `run_deferred`
+///
+/// This is intended to be called from an interrupt handler to move work out
+/// of interrupt context. The queue has a fixed capacity of
+/// `DEFERRED_CAPACITY` items. If the queue is full `work` is handed back in
+/// the `Err` variant.
+pub fn defer(work: fn()) -> Result<(), fn()> {
+    free(|cs| {
+        let mut queue = DEFERRED.borrow(cs).borrow_mut();
+
+        if queue.len == DEFERRED_CAPACITY {
+            return Err(work);
+        }
+
+        let tail = (queue.head + queue.len) % DEFERRED_CAPACITY;
+        queue.work[tail] = Some(work);
+        queue.len += 1;
+
+        Ok(())
+    })
+}
+
+/// Runs all the work queued by `defer`, oldest first
+///
+/// Each item is taken off the queue inside a critical section and then run
+/// with the caller's interrupt mask in place. Work deferred while this
+/// function is running is run before it returns.
+///
+/// This should be called from the main loop, outside interrupt context.
+pub fn run_deferred() {
+    while let Some(work) = next_deferred() {
+        work();
+    }
+}
+
+fn next_deferred() -> Option<fn()> {
+    free(|cs| {
+        let mut queue = DEFERRED.borrow(cs).borrow_mut();
+
+        if queue.len == 0 {
+            return None;
+        }
+
+        let head = queue.head;
+        let work = queue.work[head].take();
+        queue.head = (head + 1) % DEFERRED_CAPACITY;
+        queue.len -= 1;
+
+        work
+    })
+}
+
+/// The reason a trap was raised
+///
+/// PicoRV32 has no cause register. Instead traps are reported on two of the
+/// built-in IRQ lines and the cause is given by which of those lines is
+/// pending. `trap_cause` can only tell the two lines apart. The instruction
+/// behind a line 1 trap is decoded by `instruction_trap_cause`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TrapCause {
+    /// An `ebreak` or `ecall` instruction, or an illegal instruction, was
+    /// executed (IRQ line 1), and which one is unknown
+    Instruction,
+    /// An `ebreak` or `c.ebreak` instruction was executed (IRQ line 1)
+    Ebreak,
+    /// An `ecall` instruction was executed (IRQ line 1)
+    Ecall,
+    /// An illegal instruction was executed (IRQ line 1)
+    IllegalInstruction,
+    /// A bus error or a misaligned memory access occurred (IRQ line 2)
+    BusError,
+}
+
+/// Decodes the trap cause from a bitmask of pending IRQs
+///
+/// `pending` should be the bitmask handed to the interrupt handler in `q1`.
+/// Returns `None` if neither of the trap lines is pending. If both are
+/// pending the instruction trap is reported.
+pub fn trap_cause(pending: u32) -> Option<TrapCause> {
+    if pending & (1 << EBREAK_IRQ) != 0 {
+        Some(TrapCause::Instruction)
+    } else if pending & (1 << BUS_ERROR_IRQ) != 0 {
+        Some(TrapCause::BusError)
+    } else {
+        None
+    }
+}
+
+/// Decodes which instruction raised a line 1 trap
+///
+/// `return_address` is the value of `q0` on entry to the trap handler (see
+/// `return_address`). The core sets bit 0 of it when the trapping
+/// instruction was a compressed one, which gives the address of the
+/// instruction. It is read back from memory and classified as `Ebreak`,
+/// `Ecall` or `IllegalInstruction`. If the length flag in bit 0 and the
+/// instruction read back disagree, `Instruction` is returned.
+///
+/// This needs a core built with `ENABLE_IRQ` and `CATCH_ILLINSN`, so that
+/// these instructions raise line 1 at all, and the unmodified `q0` of the
+/// trap. Without `ENABLE_IRQ_QREGS` the entry code has to save `q0` (held in
+/// `x3` then) before anything overwrites it.
+///
+/// # Safety
+///
+/// - `return_address` must be the `q0` of a line 1 trap, so that the
+///   instruction it points back to can be read
+pub unsafe fn instruction_trap_cause(return_address: u32) -> TrapCause {
+    let compressed = return_address & 1 != 0;
+    let pc = if compressed {
+        return_address - 3
+    } else {
+        return_address - 4
+    };
+
+    classify_instruction(pc as usize as *const u16, compressed)
+}
+
+/// Classifies the instruction at `pc`, which the core flagged as compressed
+/// or not
+///
+/// # Safety
+///
+/// - `pc` must be readable for two bytes, or four when `compressed` is false
+///   and the low half is that of an uncompressed instruction
+unsafe fn classify_instruction(pc: *const u16, compressed: bool) -> TrapCause {
+    const EBREAK: u32 = 0x0010_0073;
+    const ECALL: u32 = 0x0000_0073;
+    const C_EBREAK: u32 = 0x9002;
+
+    let low = u32::from(read_volatile(pc));
+
+    if (low & 0b11 != 0b11) != compressed {
+        return TrapCause::Instruction;
+    }
+
+    let instruction = if compressed {
+        low
+    } else {
+        low | u32::from(read_volatile(pc.add(1))) << 16
+    };
+
+    match instruction {
+        EBREAK | C_EBREAK => TrapCause::Ebreak,
+        ECALL => TrapCause::Ecall,
+        _ => TrapCause::IllegalInstruction,
+    }
+}
+
+/// Proof that interrupts are disabled
+///
+/// Functions that must run with all interrupts masked (for example flash
+/// programming sequences) can take `&AssertDisabled` to make that requirement
+/// part of their signature. The token can only be created from a
+/// `CriticalSection` and cannot outlive it.
+///
+/// In debug builds the mask is checked when the token is dropped, and on
+/// every call to `check`, catching code that re-enabled interrupts in the
+/// middle of the critical section. The check is skipped in the handlers
+/// called by the `interrupt_vector!` entry: the core takes no interrupt
+/// until `retirq` there whatever the mask says, so the mask of the
+/// interrupted code proves nothing (see `in_interrupt`).
+///
+/// Making a `CriticalSection` out of thin air takes `unsafe`:
+///
+/// ```compile_fail,E0133
+/// use coucal_rs::interrupt::{AssertDisabled, CriticalSection};
+///
+/// let token = AssertDisabled::new(CriticalSection::new());
+/// ```
+///
+/// and the token cannot be smuggled out of `free`:
+///
+/// ```compile_fail
+/// use coucal_rs::interrupt::{self, AssertDisabled};
+///
+/// let token = interrupt::free(|cs| AssertDisabled::new(cs));
+/// token.check();
+/// ```
+#[must_use]
+pub struct AssertDisabled<'cs> {
+    cs: CriticalSection<'cs>,
+}
+
+impl<'cs> AssertDisabled<'cs> {
+    /// Creates the token for the duration of the critical section `cs`
+    pub fn new(cs: CriticalSection<'cs>) -> Self {
+        AssertDisabled { cs }
+    }
+
+    /// Checks that all interrupts are still masked
+    ///
+    /// The mask is read without changing it. This does nothing in release
+    /// builds or in an interrupt handler.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds this panics if any interrupt has been unmasked.
+    #[inline]
+    pub fn check(&self) {
+        if cfg!(debug_assertions) && !IN_INTERRUPT.borrow(self.cs).get() {
+            let mask = current_mask();
+
+            assert!(
+                mask == 0xffff_ffff,
+                "interrupts re-enabled inside a critical section (mask {:#010x})",
+                mask
+            );
+        }
+    }
+}
+
+impl<'cs> Drop for AssertDisabled<'cs> {
+    fn drop(&mut self) {
+        self.check();
+    }
+}
+
+/// An interrupt handler that can be installed in a `HandlerTable`
+pub type Handler = fn(&HandlerContext);
+
+/// Information about the interrupt being serviced, passed to each `Handler`
+pub struct HandlerContext {
+    line: u8,
+    pending: u32,
+}
+
+impl HandlerContext {
+    /// Returns the IRQ line being serviced
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+
+    /// Returns the bitmask of IRQs that were pending when dispatch started
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+
+    /// Takes the word last written by `post_message`, leaving zero in its
+    /// place
+    ///
+    /// See `post_message` for the single slot semantics.
+    ///
+    /// Note: this function is only available when q-registers are enabled.
+    #[cfg(feature = "interrupts-qregs")]
+    pub fn take_message(&self) -> u32 {
+        unsafe {
+            let word = getq2();
+            setq2(0);
+
+            word
+        }
+    }
+
+    /// Runs `f` with exclusive use of the `q2` and `q3` scratch registers
+    ///
+    /// The values of `q2` and `q3` are saved before `f` runs and restored
+    /// after it returns, so the handler can use them freely without
+    /// corrupting a use by the interrupted code (or a message from
+    /// `post_message`).
+    ///
+    /// Note: this function is only available when q-registers are enabled.
+    #[cfg(feature = "interrupts-qregs")]
+    pub fn with_scratch<R>(&self, f: impl FnOnce(&mut ScratchRegs) -> R) -> R {
+        let (q2, q3) = unsafe { (getq2(), getq3()) };
+
+        let r = f(&mut ScratchRegs { _private: () });
+
+        unsafe {
+            setq2(q2);
+            setq3(q3);
+        }
+
+        r
+    }
+}
+
+/// Scoped access to the `q2` and `q3` scratch registers
+///
+/// See `HandlerContext::with_scratch`.
+///
+/// Note: this type is only available when q-registers are enabled.
+#[cfg(feature = "interrupts-qregs")]
+pub struct ScratchRegs {
+    _private: (),
+}
+
+#[cfg(feature = "interrupts-qregs")]
+impl ScratchRegs {
+    /// Returns the value of `q2`
+    pub fn q2(&self) -> u32 {
+        unsafe { getq2() }
+    }
+
+    /// Writes `val` to `q2`
+    pub fn set_q2(&mut self, val: u32) {
+        unsafe { setq2(val) }
+    }
+
+    /// Returns the value of `q3`
+    pub fn q3(&self) -> u32 {
+        unsafe { getq3() }
+    }
+
+    /// Writes `val` to `q3`
+    pub fn set_q3(&mut self, val: u32) {
+        unsafe { setq3(val) }
+    }
+}
+
+/// Returns the address the current interrupt will return to
+///
+/// The core saves the program counter of the interrupted code in `q0` when
+/// it enters the interrupt handler, so this is the address to report when a
+/// trap handler wants to say where a fault happened. For a trap raised by an
+/// instruction it points just past that instruction, with bit 0 set if the
+/// instruction was compressed (see `instruction_trap_cause`).
+///
+/// The value is only meaningful inside an interrupt handler, before
+/// `retirq` and before anything else writes `q0`. Called anywhere else it
+/// returns whatever the last interrupt left behind.
+///
+/// ```ignore
+/// fn on_bus_error(ctx: &HandlerContext) {
+///     log::error!("bus error at {:#010x}", interrupt::return_address());
+/// }
+/// ```
+///
+/// Note: this function is only available when q-registers are enabled.
+#[cfg(feature = "interrupts-qregs")]
+pub fn return_address() -> u32 {
+    unsafe { getq0() }
+}
+
+/// The interrupt state the core keeps in `q0` and `q1`
+///
+/// The core overwrites both registers when it takes the next interrupt, so a
+/// handler that lets other interrupts in (for example by enabling a nested
+/// handler in its entry code) must save them first and put them back before
+/// `retirq`:
+///
+/// ```ignore
+/// let frame = interrupt::save_frame();
+/// // ... let a second interrupt be taken and return ...
+/// unsafe { interrupt::restore_frame(&frame) };
+/// ```
+///
+/// Saving is `getq rd, q0` followed by `getq rd, q1`, and restoring is
+/// `setq q0, rs` followed by `setq q1, rs`.
+///
+/// Note: this type is only available when q-registers are enabled.
+#[cfg(feature = "interrupts-qregs")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrapFrame {
+    /// Return address of the interrupted code (`q0`)
+    pub pc: u32,
+    /// Bitmask of the IRQs being handled (`q1`)
+    pub irqs: u32,
+}
+
+/// Reads `q0` and `q1` into a `TrapFrame`
+///
+/// Like `return_address`, this is only meaningful inside an interrupt
+/// handler, before another interrupt has been taken.
+///
+/// Note: this function is only available when q-registers are enabled.
+#[cfg(feature = "interrupts-qregs")]
+pub fn save_frame() -> TrapFrame {
+    unsafe {
+        TrapFrame {
+            pc: getq0(),
+            irqs: getq1(),
+        }
+    }
+}
+
+/// Writes a `TrapFrame` back to `q0` and `q1`
+///
+/// Note: this function is only available when q-registers are enabled.
+///
+/// # Safety
+///
+/// - `retirq` returns to `frame.pc`, so `frame` must be the one saved by the
+///   current handler, and no interrupt may be taken between the restore and
+///   the `retirq`
+#[cfg(feature = "interrupts-qregs")]
+pub unsafe fn restore_frame(frame: &TrapFrame) {
+    setq::<0>(frame.pc);
+    setq::<1>(frame.irqs);
+}
+
+/// Posts a message word for an interrupt handler to read with
+/// `HandlerContext::take_message`
+///
+/// The message is held in the `q2` q-register, which is faster than going
+/// through memory. There is a single global slot: posting again before the
+/// handler has taken the message overwrites it, and the last writer wins.
+/// Senders must coordinate between themselves, for example by only posting
+/// from one context.
+///
+/// The interrupt entry code must not use `q2` as scratch (the PicoRV32
+/// example firmware does), otherwise the message is clobbered before the
+/// handler runs.
+///
+/// Note: this function is only available when q-registers are enabled.
+#[cfg(feature = "interrupts-qregs")]
+pub fn post_message(word: u32) {
+    unsafe {
+        setq2(word);
+    }
+}
+
+/// A table of interrupt handlers indexed by IRQ line
+///
+/// Tables are built at compile time and installed with `swap_table`:
+///
+/// ```ignore
+/// static TABLE: HandlerTable = HandlerTable::new()
+///     .with(0, on_timer)
+///     .with(4, on_uart);
+/// ```
+pub struct HandlerTable {
+    handlers: [Option<Handler>; 32],
+}
+
+impl HandlerTable {
+    /// Creates a table with no handlers
+    pub const fn new() -> Self {
+        HandlerTable {
+            handlers: [None; 32],
+        }
+    }
+
+    /// Returns the table with `handler` installed for IRQ line `line`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `line` is not less than 32.
+    pub const fn with(mut self, line: u8, handler: Handler) -> Self {
+        self.handlers[line as usize] = Some(handler);
+        self
+    }
+
+    /// Returns the handler installed for IRQ line `line`, if any
+    pub fn handler(&self, line: u8) -> Option<Handler> {
+        self.handlers
+            .get(line as usize)
+            .and_then(|handler| *handler)
+    }
+}
+
+impl Default for HandlerTable {
+    fn default() -> Self {
+        HandlerTable::new()
+    }
+}
+
+static EMPTY_TABLE: HandlerTable = HandlerTable::new();
+
+static ACTIVE_TABLE: Mutex<Cell<&'static HandlerTable>> = Mutex::new(Cell::new(&EMPTY_TABLE));
+
+/// The built-in trap lines: 1 for ebreak, ecall and illegal instructions and
+/// 2 for bus errors
+const TRAP_LINES: u32 = (1 << EBREAK_IRQ) | (1 << BUS_ERROR_IRQ);
+
+/// The order in which `dispatch` services lines that are pending together
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DispatchOrder {
+    /// Service the trap lines (1 and 2) first, then every other line in
+    /// ascending order
+    ///
+    /// This is the default, so a crash is never held up behind a peripheral.
+    #[default]
+    FaultsFirst,
+    /// Service every line in ascending order
+    Ascending,
+}
+
+static DISPATCH_ORDER: Mutex<Cell<DispatchOrder>> =
+    Mutex::new(Cell::new(DispatchOrder::FaultsFirst));
+
+/// Sets the order in which `dispatch` services lines that are pending
+/// together
+pub fn set_dispatch_order(order: DispatchOrder) {
+    free(|cs| DISPATCH_ORDER.borrow(cs).set(order));
+}
+
+/// Installs `new` as the active dispatch table and returns the old one
+///
+/// The swap happens inside a critical section so an interrupt can never be
+/// dispatched through a partially updated table. Until a table is installed
+/// the active table has no handlers.
+pub fn swap_table(new: &'static HandlerTable) -> &'static HandlerTable {
+    free(|cs| ACTIVE_TABLE.borrow(cs).replace(new))
+}
+
+/// Calls the handler in the active table for each line set in `pending`
+///
+/// This is intended to be called from the interrupt handler with the
+/// bitmask of pending IRQs the core places in `q1`. Lines are serviced in
+/// the order set by `set_dispatch_order`, by default the trap lines first
+/// and then the rest in ascending order. Lines without a handler are
+/// ignored.
+///
+/// The core clears the pending bits of the lines reported in `q1` when it
+/// enters the interrupt handler, so they are not reported again unless they
+/// go pending a second time.
+pub fn dispatch(pending: u32) {
+    let (table, order, state) = free(|cs| {
+        (
+            ACTIVE_TABLE.borrow(cs).get(),
+            DISPATCH_ORDER.borrow(cs).get(),
+            DEBUG_STATE.borrow(cs).get() as *mut DebugState,
+        )
+    });
+
+    match order {
+        DispatchOrder::FaultsFirst => {
+            dispatch_lines(table, pending & TRAP_LINES, pending, state);
+            dispatch_lines(table, pending & !TRAP_LINES, pending, state);
+        }
+        DispatchOrder::Ascending => dispatch_lines(table, pending, pending, state),
+    }
+
+    publish_dispatch(state, pending);
+}
+
+static IN_INTERRUPT: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Returns `true` when called from an interrupt handler
+///
+/// This is for code that is called both from the main program and from
+/// handlers, and needs to know which. PicoRV32 has no flag for it, so the
+/// entry generated by `interrupt_vector!` (or `#[interrupt]`) sets one
+/// before calling the first handler and clears it after the last. The answer is therefore only right
+/// if every interrupt is taken through that entry: handlers called from a
+/// hand-written entry, or through `dispatch`, see `false`.
+///
+/// The flag is global, which assumes a single hart; PicoRV32 has no others.
+pub fn in_interrupt() -> bool {
+    free(|cs| IN_INTERRUPT.borrow(cs).get())
+}
+
+/// The fast path installed with `set_fast_handler`
+#[derive(Clone, Copy)]
+struct FastHandler {
+    line: u8,
+    handler: fn(),
+}
+
+static FAST_HANDLER: Mutex<Cell<Option<FastHandler>>> = Mutex::new(Cell::new(None));
+
+/// Installs `handler` as the fast path for IRQ line `line`
+///
+/// When the entry generated by `interrupt_vector!` finds that `line` is the
+/// only pending line, it calls `handler` straight away and returns, skipping
+/// the general dispatch: no handler lookup, no `DefaultHandler`, no stats or
+/// trace events, and no update of the published `DebugState`. This is
+/// meant for the one line whose latency matters most, such as a motor
+/// control tick. When other lines are pending too, `handler` is still called
+/// first and the other lines are then dispatched as usual, so `line` doesn't
+/// need an entry in the vector.
+///
+/// The registers saved by the entry are the same on both paths: `handler` is
+/// an ordinary Rust function, so the caller-saved registers have to be saved
+/// before it can be called at all. What the fast path saves is the dispatch
+/// work. To keep it fast, `handler` should be short and must not wait for
+/// anything, as it runs with interrupts disabled like every other handler.
+///
+/// Installing a fast handler replaces the previous one. There is only one
+/// fast line; see `clear_fast_handler` to remove it.
+///
+/// `line` must be less than 32.
+pub fn set_fast_handler(line: u8, handler: fn()) {
+    debug_assert!(line < 32, "IRQ number {} out of range", line);
+
+    free(|cs| {
+        FAST_HANDLER
+            .borrow(cs)
+            .set(Some(FastHandler { line, handler }))
+    });
+}
+
+/// Removes the fast handler installed by `set_fast_handler`
+///
+/// Its line is dispatched through the vector again.
+pub fn clear_fast_handler() {
+    free(|cs| FAST_HANDLER.borrow(cs).set(None));
+}
+
+/// Calls the fast handler if its line is set in `pending`
+///
+/// Returns the lines that are left for the general dispatch.
+#[doc(hidden)]
+pub fn __vector_fast(pending: u32) -> u32 {
+    // The core doesn't take another interrupt before `retirq`.
+    let cs = unsafe { CriticalSection::new() };
+
+    match FAST_HANDLER.borrow(cs).get() {
+        Some(fast) if pending & (1 << fast.line) != 0 => {
+            IN_INTERRUPT.borrow(cs).set(true);
+            (fast.handler)();
+            IN_INTERRUPT.borrow(cs).set(false);
+
+            pending & !(1 << fast.line)
+        }
+        _ => pending,
+    }
+}
+
+/// Returns `true` if no two of `values` are equal
+///
+/// This is the duplicate check of `peripheral_irq_map!`.
+#[doc(hidden)]
+pub const fn __all_distinct(values: &[u32]) -> bool {
+    let mut i = 0;
+
+    while i < values.len() {
+        let mut j = i + 1;
+
+        while j < values.len() {
+            if values[i] == values[j] {
+                return false;
+            }
+
+            j += 1;
+        }
+
+        i += 1;
+    }
+
+    true
+}
+
+/// Calls `handler` with `pending`, as the entry generated by `#[interrupt]`
+#[doc(hidden)]
+pub fn __vector_handle(pending: u32, handler: fn(u32)) {
+    // The core doesn't take another interrupt before `retirq`.
+    let cs = unsafe { CriticalSection::new() };
+
+    IN_INTERRUPT.borrow(cs).set(true);
+    handler(pending);
+    IN_INTERRUPT.borrow(cs).set(false);
+}
+
+/// Calls the handler `handler` returns for each line set in `pending`, in
+/// ascending order, or `DefaultHandler` if it returns `None`
+///
+/// This is the dispatch loop of the entry generated by `interrupt_vector!`.
+/// Like `dispatch`, it records trace events and stats and updates the
+/// published `DebugState` for the lines it services.
+#[doc(hidden)]
+pub fn __vector_dispatch<F>(pending: u32, handler: F)
+where
+    F: Fn(u8) -> Option<Handler>,
+{
+    // The core doesn't take another interrupt before `retirq`, so the whole
+    // handler is a critical section.
+    let cs = unsafe { CriticalSection::new() };
+    IN_INTERRUPT.borrow(cs).set(true);
+
+    let state = DEBUG_STATE.borrow(cs).get() as *mut DebugState;
+
+    for line in pending_lines(pending) {
+        #[cfg(feature = "stats")]
+        stats::record(line);
+
+        match handler(line) {
+            Some(handler) => {
+                #[cfg(feature = "trace")]
+                trace::record_in(cs, trace::TraceEvent::InterruptEnter { line });
+
+                handler(&HandlerContext { line, pending });
+
+                #[cfg(feature = "trace")]
+                trace::record_in(cs, trace::TraceEvent::InterruptExit { line });
+
+                publish_line(state, line);
+            }
+            None => call_default_handler(line),
+        }
+    }
+
+    publish_dispatch(state, pending);
+
+    IN_INTERRUPT.borrow(cs).set(false);
+}
+
+/// Calls `DefaultHandler`, which the application can override
+fn call_default_handler(line: u8) {
+    match () {
+        #[cfg(riscv)]
+        () => {
+            extern "C" {
+                fn DefaultHandler(line: u8);
+            }
+
+            unsafe { DefaultHandler(line) }
+        }
+
+        #[cfg(not(riscv))]
+        () => default_handler(line),
+    }
+}
+
+/// The `DefaultHandler` used when the application doesn't define one
+///
+/// An `ebreak` while the interrupt handler is running can't be serviced, so
+/// `abort` stops the core with its `trap` output raised.
+extern "C" fn default_handler(_line: u8) {
+    abort()
+}
+
+// `DefaultHandler` is a weak alias of `default_handler`, so an application
+// replaces it by defining its own `#[no_mangle] extern "C" fn
+// DefaultHandler(line: u8)`.
+#[cfg(riscv)]
+core::arch::global_asm!(
+    ".section .text.DefaultHandler, \"ax\", @progbits",
+    ".weak DefaultHandler",
+    ".type DefaultHandler, @function",
+    "DefaultHandler:",
+    "tail {default_handler}",
+    default_handler = sym default_handler,
+);
+
+/// A table of interrupt handlers that can be changed at run time
+///
+/// `HandlerTable`s are fixed at compile time. A `Dispatcher` lives in RAM,
+/// normally in a `static`, and handlers are registered and removed while the
+/// program runs:
+///
+/// ```ignore
+/// static DISPATCHER: Dispatcher = Dispatcher::new();
+///
+/// DISPATCHER.register(4, on_uart);
+///
+/// // In the interrupt handler
+/// DISPATCHER.dispatch(pending);
+/// ```
+///
+/// Each entry is read and written inside `free`, so an interrupt never sees
+/// a half written entry. Handlers are called outside that critical section,
+/// but the core does not take another interrupt until `retirq`, so when
+/// `dispatch` is called from the interrupt handler the handlers still run
+/// with interrupts effectively masked. A handler must not call `dispatch`
+/// on the same dispatcher. It may register or remove handlers, which takes
+/// effect for the lines that have not been dispatched yet.
+pub struct Dispatcher {
+    handlers: Mutex<[Cell<Option<Handler>>; 32]>,
+}
+
+impl Dispatcher {
+    /// Creates a dispatcher with no handlers
+    pub const fn new() -> Self {
+        Dispatcher {
+            handlers: Mutex::new([const { Cell::new(None) }; 32]),
+        }
+    }
+
+    /// Registers `handler` for IRQ line `line` and returns the handler it
+    /// replaces, if any
+    ///
+    /// # Panics
+    ///
+    /// Panics if `line` is not less than 32.
+    pub fn register(&self, line: u8, handler: Handler) -> Option<Handler> {
+        free(|cs| self.handlers.borrow(cs)[line as usize].replace(Some(handler)))
+    }
+
+    /// Removes the handler for IRQ line `line` and returns it, if any
+    ///
+    /// # Panics
+    ///
+    /// Panics if `line` is not less than 32.
+    pub fn unregister(&self, line: u8) -> Option<Handler> {
+        free(|cs| self.handlers.borrow(cs)[line as usize].take())
+    }
+
+    /// Calls the registered handler for each line set in `pending`, in
+    /// ascending order
+    ///
+    /// Lines without a handler are ignored.
+    pub fn dispatch(&self, pending: u32) {
+        for line in pending_lines(pending) {
+            #[cfg(feature = "stats")]
+            stats::record(line);
+
+            let handler = free(|cs| self.handlers.borrow(cs)[line as usize].get());
+
+            if let Some(handler) = handler {
+                #[cfg(feature = "trace")]
+                trace::record(trace::TraceEvent::InterruptEnter { line });
+
+                handler(&HandlerContext { line, pending });
+
+                #[cfg(feature = "trace")]
+                trace::record(trace::TraceEvent::InterruptExit { line });
+            }
+        }
+    }
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Dispatcher::new()
+    }
+}
+
+/// Iterator over the set bits of an IRQ bitmask
+///
+/// Returned by `pending_lines`. Yields the line numbers in ascending order.
+#[derive(Clone, Debug)]
+pub struct PendingIter {
+    bits: u32,
+}
+
+impl Iterator for PendingIter {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.bits == 0 {
+            return None;
+        }
+
+        let line = self.bits.trailing_zeros() as u8;
+        // Clear the lowest set bit
+        self.bits &= self.bits - 1;
+
+        Some(line)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.bits.count_ones() as usize;
+
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for PendingIter {}
+
+impl FusedIterator for PendingIter {}
+
+/// Returns an iterator over the lines set in `bits`
+///
+/// `bits` is a bitmask of IRQ lines, such as the pending bitmask returned by
+/// `waitirq`:
+///
+/// ```ignore
+/// for line in interrupt::pending_lines(unsafe { asm::waitirq() }) {
+///     service(line);
+/// }
+/// ```
+pub fn pending_lines(bits: u32) -> PendingIter {
+    PendingIter { bits }
+}
+
+/// Calls the handler in `table` for each line set in `lines`, in ascending
+/// order, recording each serviced line in `state` if it is not null
+fn dispatch_lines(table: &HandlerTable, lines: u32, pending: u32, state: *mut DebugState) {
+    for line in pending_lines(lines) {
+        #[cfg(feature = "stats")]
+        stats::record(line);
+
+        if let Some(handler) = table.handler(line) {
+            #[cfg(feature = "trace")]
+            trace::record(trace::TraceEvent::InterruptEnter { line });
+
+            handler(&HandlerContext { line, pending });
+
+            #[cfg(feature = "trace")]
+            trace::record(trace::TraceEvent::InterruptExit { line });
+
+            publish_line(state, line);
+        }
+    }
+}
+
+/// Counts a serviced line in the published `DebugState`, if there is one
+fn publish_line(state: *mut DebugState, line: u8) {
+    if !state.is_null() {
+        unsafe {
+            let count = addr_of_mut!((*state).counts[line as usize]);
+            write_volatile(count, read_volatile(count).wrapping_add(1));
+            write_volatile(addr_of_mut!((*state).last_line), u32::from(line));
+        }
+    }
+}
+
+/// Records a finished dispatch in the published `DebugState`, if there is
+/// one
+fn publish_dispatch(state: *mut DebugState, pending: u32) {
+    if !state.is_null() {
+        unsafe {
+            // Read the mask by masking everything and writing it back.
+            let mask = maskirq(0xffff_ffff);
+            maskirq(mask);
+
+            write_volatile(addr_of_mut!((*state).mask), mask);
+            write_volatile(addr_of_mut!((*state).pending), pending);
+
+            let dispatches = read_volatile(addr_of!((*state).dispatches));
+            write_volatile(
+                addr_of_mut!((*state).dispatches),
+                dispatches.wrapping_add(1),
+            );
+        }
+    }
+}
+
+/// Interrupt state published to memory for external tools
+///
+/// Once a region is registered with `publish_state`, every call to
+/// `dispatch` and every interrupt taken through the `interrupt_vector!` entry
+/// updates it, so a debugger or host tool can poll it over JTAG without
+/// halting the core. All fields are written with volatile writes.
+///
+/// The layout is `#[repr(C)]` and stable. All fields are little-endian
+/// `u32`s:
+///
+/// | Offset | Field        | Contents                                      |
+/// |--------|--------------|-----------------------------------------------|
+/// | 0x00   | `mask`       | IRQ mask at the end of the last dispatch      |
+/// | 0x04   | `pending`    | Pending bitmask passed to the last dispatch   |
+/// | 0x08   | `last_line`  | Last line serviced, `0xffff_ffff` if none yet |
+/// | 0x0c   | `dispatches` | Number of calls to `dispatch`                 |
+/// | 0x10   | `counts`     | 32 per-line counts of serviced interrupts     |
+///
+/// The counters wrap on overflow. The struct is 144 bytes long.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DebugState {
+    /// IRQ mask at the end of the last dispatch
+    pub mask: u32,
+    /// Pending bitmask passed to the last dispatch
+    pub pending: u32,
+    /// Last line serviced, `0xffff_ffff` if no line has been serviced
+    pub last_line: u32,
+    /// Number of calls to `dispatch`
+    pub dispatches: u32,
+    /// Number of times each line has been serviced
+    pub counts: [u32; 32],
+}
+
+impl DebugState {
+    /// Returns the state published before the first dispatch
+    pub const fn new() -> Self {
+        DebugState {
+            mask: 0,
+            pending: 0,
+            last_line: 0xffff_ffff,
+            dispatches: 0,
+            counts: [0; 32],
+        }
+    }
+}
+
+impl Default for DebugState {
+    fn default() -> Self {
+        DebugState::new()
+    }
+}
+
+// Address of the published `DebugState`, zero if none is registered.
+static DEBUG_STATE: Mutex<Cell<usize>> = Mutex::new(Cell::new(0));
+
+/// Starts publishing the interrupt state to `region`
+///
+/// `region` is reset to `DebugState::new()` and from then on updated by
+/// every call to `dispatch` and every interrupt taken through the
+/// `interrupt_vector!` entry. Passing a null pointer stops publishing.
+///
+/// # Safety
+///
+/// - `region` must be null or valid for volatile writes of a `DebugState`,
+///   and stay valid until publishing is stopped
+pub unsafe fn publish_state(region: *mut DebugState) {
+    if !region.is_null() {
+        write_volatile(region, DebugState::new());
+    }
+
+    free(|cs| DEBUG_STATE.borrow(cs).set(region as usize));
+}
+
+/// Lets pending interrupts be serviced in the middle of long running code
+///
+/// This temporarily installs `mask` and then restores the previous mask. Any
+/// line that is pending and unmasked by `mask` is taken by the core as soon
+/// as `mask` is installed, so its handler runs before this function returns.
+/// This is intended for CPU bound code that runs with some or all interrupts
+/// masked and wants to stay responsive.
+///
+/// Inside an `interrupt::free` critical section every interrupt is masked and
+/// this function does nothing, so it never breaks a critical section. The
+/// same applies to any code that has masked every interrupt by other means.
+pub fn poll_and_service(mask: u32) {
+    // Masking everything is always safe and tells us what the mask was.
+    let old_mask = unsafe { maskirq(0xffff_ffff) };
+
+    if old_mask == 0xffff_ffff {
+        return;
+    }
+
+    unsafe {
+        maskirq(mask);
+        maskirq(old_mask);
+    }
+}
+
+/// Returns a mask that enables exactly the lines used by `used`
+///
+/// Every line not in `used` is masked. When `catch_faults` is `true` the
+/// built-in trap lines (1 for ebreak, ecall and illegal instructions, 2 for
+/// bus errors) are also enabled so that crashes are always caught.
+///
+/// The result is an `IrqMask`, ready for `maskirq_typed`. Its bits are in
+/// the same polarity as `maskirq`: a `0b1` disables the line.
+///
+/// Each `nr()` must be less than 32.
+pub fn mask_for_peripherals<I: Nr>(used: &[I], catch_faults: bool) -> IrqMask {
+    let mut enabled = 0;
+
+    for irq in used {
+        debug_assert!(irq.nr() < 32, "IRQ number {} out of range", irq.nr());
+
+        enabled |= 1 << irq.nr();
+    }
+
+    if catch_faults {
+        enabled |= TRAP_LINES;
+    }
+
+    IrqMask::from_bits(!enabled)
+}
+
+/// Runs `f` with the interrupts in `lines` unmasked, then restores the
+/// previous mask exactly
+///
+/// This is the inverse of `free`: it makes sure that some high priority
+/// lines (a `0b1` in `lines` selects the line) can fire during `f`, even in an
+/// otherwise masked region. Lines that are not in `lines` keep their current
+/// state.
+///
+/// # Safety
+///
+/// - `f` can be preempted by the handlers of the boosted lines. If this is
+///   called inside an `interrupt::free` critical section, those handlers must
+///   not touch anything the critical section protects
+pub unsafe fn with_boosted<F, R>(lines: u32, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let old_mask = maskirq(0xffff_ffff);
+    maskirq(old_mask & !lines);
+
+    let r = f();
+
+    maskirq(old_mask);
+
+    r
+}
+
+/// Runs `f` with the single interrupt line `irq` unmasked, then restores the
+/// previous mask exactly
+///
+/// This is `with_boosted` for one line. If the line was already unmasked
+/// nothing changes for it. There is no unwinding in `no_std`, so the mask is
+/// only restored if `f` returns.
+///
+/// `irq.nr()` must be less than 32.
+///
+/// # Safety
+///
+/// - The requirements of `with_boosted` apply to the handler of `irq`
+pub unsafe fn with_irq_enabled<I, F, R>(irq: I, f: F) -> R
+where
+    I: Nr,
+    F: FnOnce() -> R,
+{
+    debug_assert!(irq.nr() < 32, "IRQ number {} out of range", irq.nr());
+
+    with_boosted(1 << irq.nr(), f)
+}
+
+/// Static storage for the value shared by a family of `IrqRc` handles
+///
+/// The crate has no allocator, so the shared value lives in a slot provided
+/// by the caller, normally a `static`.
+pub struct IrqRcSlot<T> {
+    count: Cell<usize>,
+    value: UnsafeCell<Option<T>>,
+}
+
+impl<T> IrqRcSlot<T> {
+    /// Creates an empty slot
+    pub const fn new() -> Self {
+        IrqRcSlot {
+            count: Cell::new(0),
+            value: UnsafeCell::new(None),
+        }
+    }
+}
+
+impl<T> Default for IrqRcSlot<T> {
+    fn default() -> Self {
+        IrqRcSlot::new()
+    }
+}
+
+// The count and value are only modified inside `interrupt::free`, which on a
+// single hart gives exclusive access. The value itself is shared between
+// contexts, hence the `Send + Sync` bound.
+unsafe impl<T: Send + Sync> Sync for IrqRcSlot<T> {}
+
+/// A reference counted handle that can be cloned and dropped from both main
+/// and interrupt context
+///
+/// The reference count is adjusted inside `interrupt::free`, which makes it
+/// safe without atomics. This relies on there being a single hart: on a
+/// multi-core system masking interrupts does not give exclusive access.
+///
+/// The value is dropped exactly once, when the last handle is dropped. The
+/// slot can then be reused by `IrqRc::new`.
+pub struct IrqRc<T: 'static> {
+    slot: &'static IrqRcSlot<T>,
+}
+
+impl<T> IrqRc<T> {
+    /// Moves `value` into `slot` and returns the first handle to it
+    ///
+    /// If `slot` still holds a value from live handles, `value` is handed back
+    /// in the `Err` variant.
+    pub fn new(slot: &'static IrqRcSlot<T>, value: T) -> Result<Self, T> {
+        free(|_| {
+            if slot.count.get() != 0 {
+                return Err(value);
+            }
+
+            unsafe {
+                *slot.value.get() = Some(value);
+            }
+            slot.count.set(1);
+
+            Ok(IrqRc { slot })
+        })
+    }
+
+    /// Returns the number of handles to the value
+    pub fn strong_count(this: &Self) -> usize {
+        free(|_| this.slot.count.get())
+    }
+}
+
+impl<T> Clone for IrqRc<T> {
+    fn clone(&self) -> Self {
+        free(|_| self.slot.count.set(self.slot.count.get() + 1));
+
+        IrqRc { slot: self.slot }
+    }
+}
+
+impl<T> Drop for IrqRc<T> {
+    fn drop(&mut self) {
+        let value = free(|_| {
+            let count = self.slot.count.get() - 1;
+            self.slot.count.set(count);
+
+            if count == 0 {
+                unsafe { (*self.slot.value.get()).take() }
+            } else {
+                None
+            }
+        });
+
+        // Run the value's destructor with interrupts back in their previous
+        // state.
+        drop(value);
+    }
+}
+
+impl<T> Deref for IrqRc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // The value is only written while the count is zero, so it is present
+        // and unchanging for as long as this handle exists.
+        match unsafe { &*self.slot.value.get() } {
+            Some(value) => value,
+            None => unreachable!(),
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for IrqRc<T> {}
+unsafe impl<T: Send + Sync> Sync for IrqRc<T> {}
+
+/// Read-modify-writes the memory-mapped register at `addr`
+///
+/// The register is read with a volatile read, passed to `f`, and the result
+/// written back with a volatile write, all inside `interrupt::free`. This
+/// stops an interrupt handler that touches the same register from having
+/// its update lost in between the read and the write.
+///
+/// `f` runs inside the critical section so it should be short.
+///
+/// # Safety
+///
+/// - `addr` must be valid for volatile reads and writes of a `u32` and be
+///   4-byte aligned
+pub unsafe fn mmio_modify<F>(addr: *mut u32, f: F)
+where
+    F: FnOnce(u32) -> u32,
+{
+    debug_assert!(
+        addr as usize & 0b11 == 0,
+        "unaligned MMIO register address {:p}",
+        addr
+    );
+
+    free(|_| {
+        let value = read_volatile(addr);
+        write_volatile(addr, f(value));
+    });
+}
+
+/// Stores `new` in `cell` if it holds `current`
+///
+/// Returns the previous value, as `Ok` if it was `current` and `Err` with
+/// the value found otherwise. The compare and the store happen inside
+/// `interrupt::free`, so on a single hart this is the compare-and-swap that
+/// the missing A extension would provide: no interrupt handler can write the
+/// cell in between.
+///
+/// ```ignore
+/// let mut current = count.get();
+///
+/// // Retry with the new value if a handler changed it meanwhile
+/// while let Err(actual) = interrupt::cas(count, current, current + 1) {
+///     current = actual;
+/// }
+/// ```
+pub fn cas(cell: &Cell<u32>, current: u32, new: u32) -> Result<u32, u32> {
+    free(|_| {
+        let value = cell.get();
+
+        if value == current {
+            cell.set(new);
+
+            Ok(value)
+        } else {
+            Err(value)
+        }
+    })
+}
+
+/// Services a bursty line, batching events that arrive close together
+///
+/// This is intended to be called from the interrupt handler for `line`. It
+/// runs `handler` for the event that caused the interrupt and then arms the
+/// timer for `window_cycles`. If `line` goes pending again before the timer
+/// expires `handler` is run again and the window restarts. The batch ends
+/// when the window expires without the line re-firing. Returns the number of
+/// times `handler` was run.
+///
+/// Batching amortises the handler entry and exit cost over several events,
+/// at the price of holding the core in the handler for up to
+/// `window_cycles` after the last event. Every other interrupt is delayed by
+/// at least that much.
+///
+/// The line must be configured as not latched (`LATCHED_IRQ` in the core),
+/// so that its pending bit clears when `handler` services the peripheral. A
+/// latched line stays pending once it re-fires and the batch never ends.
+///
+/// The timer is taken over: any running countdown is discarded and the
+/// timer line is left pending when the final window expires. If the timer
+/// line is already pending when this is called the batch ends after a
+/// single event.
+///
+/// `line` must be between 1 and 31, since line 0 is the timer.
+///
+/// Note: this function is only available when the `timer` feature is enabled.
+#[cfg(feature = "timer")]
+pub fn coalesce<F>(line: u8, window_cycles: u32, mut handler: F) -> u32
+where
+    F: FnMut(),
+{
+    debug_assert!(
+        line != 0 && line < 32,
+        "IRQ line {} cannot be coalesced",
+        line
+    );
+
+    let line_bit = 1 << line;
+    let mut events = 0;
+
+    loop {
+        handler();
+        events += 1;
+
+        unsafe {
+            timer(window_cycles);
+        }
+
+        // Wait until either the line re-fires or the window expires. Other
+        // lines going pending also wake `waitirq`, so keep waiting until one
+        // of the two we care about is set.
+        let pending = loop {
+            let pending = unsafe { waitirq() };
+
+            if pending & (line_bit | 1) != 0 {
+                break pending;
+            }
+        };
+
+        if pending & line_bit == 0 {
+            break;
+        }
+    }
+
+    events
+}
+
+/// Blocks until one of the interrupts in `lines` is pending
+///
+/// A `0b1` in `lines` selects the line. Returns the bitmask of pending IRQs
+/// seen by the wakeup, with at least one of `lines` set, and whichever other
+/// lines happened to be pending too.
+///
+/// `waitirq` wakes on any pending line, masked or not, but a line is only
+/// observed as pending until the core takes its interrupt. If a line in
+/// `lines` were unmasked while waiting, an interrupt that is already pending
+/// (or that arrives just before `waitirq`) would be serviced first and the
+/// wakeup lost. So every interrupt is masked while waiting, and the previous
+/// mask is only restored once a line in `lines` has been seen. At that point
+/// the core services whatever the restored mask allows, including the line
+/// that caused the wakeup, before this function returns.
+///
+/// If a line outside `lines` is pending and stays pending (for example
+/// because it is masked) `waitirq` returns straight away every time, and the
+/// wait turns into a busy loop until one of `lines` goes pending.
+///
+/// If none of `lines` can ever become pending this function never returns.
+pub fn wait_for(lines: u32) -> u32 {
+    let old_mask = unsafe { maskirq(0xffff_ffff) };
+
+    let pending = loop {
+        let pending = unsafe { waitirq() };
+
+        if pending & lines != 0 {
+            break pending;
+        }
+    };
+
+    unsafe {
+        maskirq(old_mask);
+    }
+
+    pending
+}
+
+/// Blocks until the interrupt `irq` is pending
+///
+/// This is `wait_for` for a single line. Other lines that are pending when
+/// it wakes are left as they are: nothing here clears or services them, and
+/// the handlers (or the caller's own dispatch) remain responsible for them.
+///
+/// `irq.nr()` must be less than 32.
+pub fn wait_for_irq<I: Nr>(irq: I) {
+    debug_assert!(irq.nr() < 32, "IRQ number {} out of range", irq.nr());
+
+    wait_for(1 << irq.nr());
+}
+
+/// Services every pending interrupt, then runs `f` in a critical section
+///
+/// All interrupts are unmasked for a moment, so the core takes every line
+/// that is pending at the time, including lines masked by the caller, and
+/// runs their handlers. Only then are all interrupts masked and `f` run with
+/// a `CriticalSection` token. Clearing the backlog before masking keeps the
+/// latency that the critical section adds to those interrupts down. The
+/// mask in place before the call is restored when `f` returns.
+///
+/// The drain is done by the core rather than by a loop in software, so
+/// there is no loop to bound: as soon as the core returns from the handlers
+/// to the next instruction, everything is masked. A line that goes pending
+/// again as fast as its handler clears it keeps the core in that handler,
+/// with or without this function.
+///
+/// Inside an enclosing critical section nothing is drained and this is the
+/// same as `free`.
+///
+/// # Safety
+///
+/// - Every line is unmasked while draining, so every pending line must have
+///   a handler that is safe to run at this point
+pub unsafe fn drain_then<F, R>(f: F) -> R
+where
+    F: FnOnce(CriticalSection) -> R,
+{
+    poll_and_service(0);
+
+    free(f)
+}
+
+/// Leaves the core in a quiescent state
+///
+/// This is intended for use before a firmware update, a reset or a low power
+/// transition. In order it:
+///
+/// 1. Disarms the timer (when the `timer` feature is enabled), so no new
+///    timer interrupt can go pending.
+/// 2. Runs the deferred work queue while interrupts are still in their
+///    current state, so work that relies on them can finish.
+/// 3. Masks every interrupt.
+/// 4. Runs the deferred work queue again, to pick up anything a handler
+///    queued between steps 2 and 3.
+///
+/// On return the timer is disarmed, every interrupt is masked and the
+/// deferred work queue is empty. The mask is not restored.
+pub fn shutdown() {
+    #[cfg(feature = "timer")]
+    unsafe {
+        timer(0);
+    }
+
+    run_deferred();
+
+    unsafe {
+        disable();
+    }
+
+    run_deferred();
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use std::vec::Vec;
+
+    fn ignore(_: &HandlerContext) {}
+
+    #[test]
+    fn drain_then_masks_everything_for_f_and_restores_the_mask() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        mock::raise(0b1_0101);
+
+        unsafe {
+            maskirq(0b1_0000);
+        }
+
+        let mask = unsafe { drain_then(|_| current_mask()) };
+
+        assert_eq!(mask, 0xffff_ffff);
+        assert_eq!(mock::mask(), 0b1_0000);
+    }
+
+    #[test]
+    fn drain_then_inside_free_keeps_everything_masked() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        mock::raise(0b110);
+
+        let mask = free(|_| unsafe { drain_then(|_| current_mask()) });
+
+        assert_eq!(mask, 0xffff_ffff);
+        assert_eq!(mock::mask(), 0xffff_ffff);
+    }
+
+    #[test]
+    fn vector_dispatch_publishes_state() {
+        let _lock = mock::test_lock();
+        mock::reset();
+
+        let mut state = DebugState::new();
+
+        unsafe {
+            publish_state(&mut state);
+        }
+
+        __vector_dispatch(0b1010, |_| Some(ignore));
+
+        unsafe {
+            publish_state(core::ptr::null_mut());
+        }
+
+        assert_eq!(state.counts[1], 1);
+        assert_eq!(state.counts[3], 1);
+        assert_eq!(state.last_line, 3);
+        assert_eq!(state.pending, 0b1010);
+        assert_eq!(state.dispatches, 1);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn vector_dispatch_traces_each_handler() {
+        use self::trace::{TraceEvent, TraceSink};
+        use std::sync::Mutex;
+
+        struct Recorder(Mutex<Vec<TraceEvent>>);
+
+        impl TraceSink for Recorder {
+            fn record(&self, event: TraceEvent) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+
+        let _lock = mock::test_lock();
+        mock::reset();
+
+        trace::set_sink(Some(&RECORDER));
+        __vector_dispatch(0b100, |_| Some(ignore));
+        trace::set_sink(None);
+
+        let events = RECORDER.0.lock().unwrap();
+        let interrupts: Vec<_> = events
+            .iter()
+            .filter(|event| !matches!(event, TraceEvent::CriticalEnter | TraceEvent::CriticalExit))
+            .collect();
+
+        assert_eq!(
+            interrupts,
+            [
+                &TraceEvent::InterruptEnter { line: 2 },
+                &TraceEvent::InterruptExit { line: 2 },
+            ]
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn assert_disabled_check_panics_once_unmasked() {
+        use std::panic::AssertUnwindSafe;
+
+        let _lock = mock::test_lock();
+        mock::reset();
+
+        let unmasked = free(|cs| {
+            let token = AssertDisabled::new(cs);
+            token.check();
+
+            unsafe {
+                maskirq(!(1 << TIMER_IRQ));
+            }
+
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| token.check()));
+            // Reading the mask didn't change it
+            assert_eq!(mock::mask(), !(1 << TIMER_IRQ));
+
+            // Mask everything again so dropping the token doesn't panic.
+            unsafe {
+                maskirq(0xffff_ffff);
+            }
+
+            result.is_err()
+        });
+
+        assert!(unmasked);
+    }
+
+    static RAN: std::sync::Mutex<Vec<u8>> = std::sync::Mutex::new(Vec::new());
+
+    fn ran() -> Vec<u8> {
+        core::mem::take(&mut *RAN.lock().unwrap())
+    }
+
+    fn first() {
+        RAN.lock().unwrap().push(1);
+    }
+
+    fn second() {
+        RAN.lock().unwrap().push(2);
+    }
+
+    fn requeue() {
+        RAN.lock().unwrap().push(3);
+        defer(first).unwrap();
+    }
+
+    #[test]
+    fn deferred_work_runs_oldest_first() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        run_deferred();
+        ran();
+
+        defer(second).unwrap();
+        defer(first).unwrap();
+        defer(requeue).unwrap();
+        run_deferred();
+
+        assert_eq!(ran(), [2, 1, 3, 1]);
+
+        run_deferred();
+
+        assert_eq!(ran(), []);
+    }
+
+    #[test]
+    fn defer_hands_back_work_when_full() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        run_deferred();
+        ran();
+
+        for _ in 0..DEFERRED_CAPACITY {
+            defer(first).unwrap();
+        }
+
+        assert!(defer(second).is_err());
+
+        run_deferred();
+
+        assert_eq!(ran(), [1; DEFERRED_CAPACITY]);
+
+        defer(second).unwrap();
+        run_deferred();
+
+        assert_eq!(ran(), [2]);
+    }
+
+    fn record_line(context: &HandlerContext) {
+        RAN.lock().unwrap().push(context.line());
+    }
+
+    fn record_offset_line(context: &HandlerContext) {
+        RAN.lock().unwrap().push(100 + context.line());
+    }
+
+    #[test]
+    fn swap_table_returns_the_old_table_and_dispatches_from_the_new() {
+        static OLD: HandlerTable = HandlerTable::new().with(3, record_line);
+        static NEW: HandlerTable = HandlerTable::new().with(3, record_offset_line);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        ran();
+
+        let before = swap_table(&OLD);
+
+        dispatch(1 << 3);
+
+        assert!(core::ptr::eq(swap_table(&NEW), &OLD));
+
+        dispatch(1 << 3);
+
+        assert!(core::ptr::eq(swap_table(before), &NEW));
+        assert_eq!(ran(), [3, 103]);
+    }
+
+    #[test]
+    fn faults_are_dispatched_first_unless_ascending_is_set() {
+        static TABLE: HandlerTable = HandlerTable::new()
+            .with(0, record_line)
+            .with(1, record_line)
+            .with(2, record_line)
+            .with(4, record_line);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        ran();
+
+        let before = swap_table(&TABLE);
+
+        dispatch(0b1_0111);
+
+        assert_eq!(ran(), [1, 2, 0, 4]);
+
+        set_dispatch_order(DispatchOrder::Ascending);
+        dispatch(0b1_0111);
+        set_dispatch_order(DispatchOrder::FaultsFirst);
+
+        assert_eq!(ran(), [0, 1, 2, 4]);
+
+        swap_table(before);
+    }
+
+    #[test]
+    fn irq_rc_drops_the_value_once_with_the_last_handle() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted;
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        static SLOT: IrqRcSlot<Counted> = IrqRcSlot::new();
+
+        mock::reset();
+
+        let first = IrqRc::new(&SLOT, Counted).ok().unwrap();
+        let second = first.clone();
+
+        assert_eq!(IrqRc::strong_count(&first), 2);
+
+        drop(first);
+
+        assert_eq!(IrqRc::strong_count(&second), 1);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+
+        drop(second);
+
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn mmio_modify_writes_back_the_new_value_with_interrupts_masked() {
+        let register = Cell::new(0b1010_u32);
+
+        mock::reset();
+
+        unsafe {
+            maskirq(0b100);
+
+            mmio_modify(register.as_ptr(), |value| {
+                assert_eq!(mock::mask(), 0xffff_ffff);
+
+                value | 0b1
+            });
+        }
+
+        assert_eq!(register.get(), 0b1011);
+        assert_eq!(mock::mask(), 0b100);
+    }
+
+    #[test]
+    fn irq_line_round_trips_through_its_number() {
+        for nr in 0..32u8 {
+            assert_eq!(IrqLine::try_from(nr).map(|line| line.nr()), Ok(nr));
+            assert_eq!(
+                IrqLine::try_from(u32::from(nr)).map(|line| line.nr()),
+                Ok(nr)
+            );
+        }
+
+        assert_eq!(IrqLine::try_from(TIMER_IRQ), Ok(IrqLine::Timer));
+        assert_eq!(IrqLine::try_from(EBREAK_IRQ), Ok(IrqLine::Ebreak));
+        assert_eq!(IrqLine::try_from(BUS_ERROR_IRQ), Ok(IrqLine::BusError));
+        assert_eq!(IrqLine::try_from(3u8), Ok(IrqLine::External(3)));
+    }
+
+    #[test]
+    fn irq_line_rejects_numbers_past_31() {
+        assert_eq!(IrqLine::try_from(32u8), Err(32));
+        assert_eq!(IrqLine::try_from(u8::MAX), Err(u8::MAX));
+        assert_eq!(IrqLine::try_from(32u32), Err(32));
+        assert_eq!(IrqLine::try_from(256u32), Err(256));
+        assert_eq!(IrqLine::try_from(u32::MAX), Err(u32::MAX));
+    }
+
+    #[test]
+    fn pending_lines_of_nothing_is_empty() {
+        let mut lines = pending_lines(0);
+
+        assert_eq!(lines.len(), 0);
+        assert_eq!(lines.next(), None);
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn pending_lines_yields_a_single_line() {
+        assert_eq!(pending_lines(1 << 31).collect::<Vec<_>>(), [31]);
+    }
+
+    #[test]
+    fn pending_lines_yields_every_line_in_ascending_order() {
+        let lines = pending_lines(0xffff_ffff);
+
+        assert_eq!(lines.len(), 32);
+        assert!(lines.eq(0..32));
+        assert_eq!(pending_lines(0b1010_0110).collect::<Vec<_>>(), [1, 2, 5, 7]);
+    }
+
+    #[test]
+    fn disable_masks_everything_and_returns_the_previous_mask() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b110);
+
+            assert_eq!(disable(), 0b110);
+            assert_eq!(mock::mask(), 0xffff_ffff);
+            assert_eq!(disable(), 0xffff_ffff);
+        }
+    }
+
+    #[test]
+    fn current_mask_leaves_the_mask_unchanged() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b1001);
+        }
+
+        assert_eq!(current_mask(), 0b1001);
+        assert_eq!(current_mask(), 0b1001);
+        assert_eq!(mock::mask(), 0b1001);
+    }
+
+    #[test]
+    fn disable_and_save_pairs_restore_in_reverse_order() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b10);
+        }
+
+        let outer = disable_and_save();
+        assert_eq!(mock::mask(), 0xffff_ffff);
+
+        let inner = disable_and_save();
+
+        unsafe {
+            restore(inner);
+            assert_eq!(mock::mask(), 0xffff_ffff);
+
+            restore(outer);
+        }
+
+        assert_eq!(mock::mask(), 0b10);
+    }
+
+    #[test]
+    fn free_skips_the_restore_when_everything_was_masked() {
+        mock::reset();
+
+        // Unmasking inside `f` shows whether `free` wrote the mask back.
+        free(|_| unsafe {
+            maskirq(0b1);
+        });
+
+        assert_eq!(mock::mask(), 0b1);
+
+        free(|_| unsafe {
+            maskirq(0);
+        });
+
+        assert_eq!(mock::mask(), 0b1);
+    }
+
+    #[test]
+    fn nested_free_keeps_everything_masked() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b100);
+        }
+
+        free(|_| {
+            free(|_| assert_eq!(mock::mask(), 0xffff_ffff));
+
+            assert_eq!(mock::mask(), 0xffff_ffff);
+        });
+
+        assert_eq!(mock::mask(), 0b100);
+    }
+
+    #[test]
+    fn mask_irqs_and_unmask_irqs_touch_only_their_lines() {
+        mock::reset();
+
+        unsafe {
+            unmask_irqs(&[IrqLine::Timer, IrqLine::External(4), IrqLine::External(31)]);
+        }
+
+        assert_eq!(mock::mask(), 0x7fff_ffee);
+
+        mask_irqs(&[IrqLine::Timer, IrqLine::External(31)]);
+
+        assert_eq!(mock::mask(), 0xffff_ffef);
+
+        mask_irqs::<IrqLine>(&[]);
+
+        assert_eq!(mock::mask(), 0xffff_ffef);
+    }
+
+    #[test]
+    fn enable_restore_keeps_lines_masked_before_disable() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b1010);
+
+            let prev = disable();
+            enable_restore(prev);
+        }
+
+        assert_eq!(mock::mask(), 0b1010);
+
+        unsafe {
+            disable();
+            enable();
+        }
+
+        assert_eq!(mock::mask(), 0);
+    }
+
+    #[cfg(feature = "interrupts-qregs")]
+    #[test]
+    fn trap_frame_round_trips_through_q0_and_q1() {
+        mock::reset();
+
+        unsafe {
+            setq::<0>(0x0000_1234);
+            setq::<1>(0b1001);
+        }
+
+        let frame = save_frame();
+
+        assert_eq!(frame.pc, 0x0000_1234);
+        assert_eq!(frame.irqs, 0b1001);
+
+        unsafe {
+            setq::<0>(0);
+            setq::<1>(0);
+
+            restore_frame(&frame);
+        }
+
+        assert_eq!(save_frame(), frame);
+    }
+
+    #[test]
+    fn cas_only_swaps_on_a_match() {
+        mock::reset();
+
+        let cell = Cell::new(1);
+
+        assert_eq!(cas(&cell, 1, 2), Ok(1));
+        assert_eq!(cell.get(), 2);
+        assert_eq!(cas(&cell, 1, 3), Err(2));
+        assert_eq!(cell.get(), 2);
+    }
+
+    #[test]
+    fn mask_for_peripherals_enables_exactly_the_used_lines() {
+        let used = [IrqLine::Timer, IrqLine::External(5), IrqLine::External(31)];
+
+        assert_eq!(
+            mask_for_peripherals(&used, false).bits(),
+            !((1 << 0) | (1 << 5) | (1 << 31))
+        );
+        assert_eq!(
+            mask_for_peripherals::<IrqLine>(&[], false),
+            IrqMask::all_masked()
+        );
+    }
+
+    #[test]
+    fn mask_for_peripherals_enables_the_trap_lines_on_request() {
+        let used = [IrqLine::External(5)];
+
+        let catching = mask_for_peripherals(&used, true);
+        let ignoring = mask_for_peripherals(&used, false);
+
+        assert!(!catching.masked(EBREAK_IRQ));
+        assert!(!catching.masked(BUS_ERROR_IRQ));
+        assert!(ignoring.masked(EBREAK_IRQ));
+        assert!(ignoring.masked(BUS_ERROR_IRQ));
+        assert_eq!(catching.bits(), !((1 << 1) | (1 << 2) | (1 << 5)));
+        assert_eq!(ignoring.bits(), !(1 << 5));
+    }
+
+    #[test]
+    fn trap_cause_decodes_the_trap_lines() {
+        assert_eq!(trap_cause(1 << EBREAK_IRQ), Some(TrapCause::Instruction));
+        assert_eq!(trap_cause(1 << BUS_ERROR_IRQ), Some(TrapCause::BusError));
+        assert_eq!(
+            trap_cause((1 << EBREAK_IRQ) | (1 << BUS_ERROR_IRQ)),
+            Some(TrapCause::Instruction)
+        );
+        assert_eq!(
+            trap_cause((1 << BUS_ERROR_IRQ) | (1 << TIMER_IRQ) | (1 << 7)),
+            Some(TrapCause::BusError)
+        );
+        assert_eq!(trap_cause(0), None);
+        assert_eq!(trap_cause((1 << TIMER_IRQ) | (1 << 7)), None);
+    }
+
+    #[test]
+    fn instruction_traps_are_classified() {
+        // Halves in memory order: ebreak, ecall, an all-zero (illegal) word,
+        // c.ebreak and an illegal compressed instruction
+        let code: [u16; 8] = [
+            0x0073, 0x0010, 0x0073, 0x0000, 0x0000, 0x0000, 0x9002, 0x0000,
+        ];
+        let at = |half: usize, compressed| unsafe {
+            classify_instruction(code.as_ptr().add(half), compressed)
+        };
+
+        assert_eq!(at(0, false), TrapCause::Ebreak);
+        assert_eq!(at(2, false), TrapCause::Ecall);
+        assert_eq!(at(6, true), TrapCause::Ebreak);
+        assert_eq!(at(7, true), TrapCause::IllegalInstruction);
+
+        // The length flag disagrees with the instruction read back
+        assert_eq!(at(0, true), TrapCause::Instruction);
+        assert_eq!(at(4, false), TrapCause::Instruction);
+        assert_eq!(at(6, false), TrapCause::Instruction);
+    }
+
+    #[test]
+    fn illegal_uncompressed_instructions_are_classified() {
+        // `unimp` as `csrrw x0, cycle, x0`, and a store with a bogus width
+        let code: [u16; 4] = [0x1073, 0xc000, 0x7023, 0x0000];
+        let at = |half: usize| unsafe { classify_instruction(code.as_ptr().add(half), false) };
+
+        assert_eq!(at(0), TrapCause::IllegalInstruction);
+        assert_eq!(at(2), TrapCause::IllegalInstruction);
+    }
+
+    #[test]
+    fn drain_then_services_pending_lines_before_f() {
+        static TABLE: HandlerTable = HandlerTable::new()
+            .with(3, record_line)
+            .with(5, record_line);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        ran();
+
+        let before = swap_table(&TABLE);
+
+        unsafe {
+            maskirq(!0b1);
+        }
+        mock::on_interrupt(Some(dispatch));
+        mock::raise((1 << 3) | (1 << 5));
+
+        let pending = unsafe {
+            drain_then(|_| {
+                RAN.lock().unwrap().push(0);
+
+                mock::pending()
+            })
+        };
+
+        assert_eq!(pending, 0);
+        assert_eq!(ran(), [3, 5, 0]);
+        assert_eq!(mock::mask(), !0b1);
+
+        swap_table(before);
+    }
+
+    #[test]
+    fn poll_and_service_does_nothing_inside_free() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        ran();
+
+        unsafe {
+            maskirq(!(1 << 3));
+        }
+        mock::on_interrupt(Some(|lines| RAN.lock().unwrap().push(lines as u8)));
+
+        free(|_| {
+            mock::raise(1 << 3);
+            poll_and_service(0);
+
+            assert_eq!(mock::mask(), 0xffff_ffff);
+            assert_eq!(mock::pending(), 1 << 3);
+            assert_eq!(ran(), []);
+        });
+
+        // Taken as soon as free restored the mask
+        assert_eq!(ran(), [1 << 3]);
+    }
+
+    #[test]
+    fn poll_and_service_installs_mask_and_restores_the_old_one() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        ran();
+
+        unsafe {
+            maskirq(!0b1);
+        }
+        mock::raise((1 << 3) | (1 << 5));
+        mock::on_interrupt(Some(|lines| {
+            RAN.lock().unwrap().push(lines as u8);
+            assert_eq!(mock::mask(), !(1 << 3));
+        }));
+
+        poll_and_service(!(1 << 3));
+
+        assert_eq!(ran(), [1 << 3]);
+        assert_eq!(mock::mask(), !0b1);
+        assert_eq!(mock::pending(), 1 << 5);
+    }
+
+    #[test]
+    fn with_boosted_unmasks_only_the_boosted_lines_during_f() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b1111_0000);
+
+            let r = with_boosted(0b0011_0000, || {
+                assert_eq!(mock::mask(), 0b1100_0000);
+
+                7
+            });
+            assert_eq!(r, 7);
+        }
+        assert_eq!(mock::mask(), 0b1111_0000);
+
+        // Boosting inside free opens only the boosted lines
+        free(|_| unsafe {
+            with_boosted(1 << 4, || assert_eq!(mock::mask(), !(1 << 4)));
+            assert_eq!(mock::mask(), 0xffff_ffff);
+        });
+        assert_eq!(mock::mask(), 0b1111_0000);
+    }
+
+    #[test]
+    fn with_irq_enabled_unmasks_one_line_during_f() {
+        mock::reset();
+
+        unsafe {
+            with_irq_enabled(IrqLine::External(9), || {
+                assert_eq!(mock::mask(), !(1 << 9));
+            });
+            assert_eq!(mock::mask(), 0xffff_ffff);
+
+            // An already unmasked line stays unmasked, and so does the rest
+            maskirq(!0b11);
+            with_irq_enabled(IrqLine::Timer, || assert_eq!(mock::mask(), !0b11));
+        }
+        assert_eq!(mock::mask(), !0b11);
+    }
+
+    #[test]
+    fn free_except_masks_everything_but_the_kept_lines_during_f() {
+        mock::reset();
+
+        unsafe {
+            maskirq(0b1010);
+        }
+
+        let keep = free_except(0b0110, |section| {
+            // Line 1 stays masked, it was masked by the caller
+            assert_eq!(mock::mask(), !0b0100);
+
+            section.keep()
+        });
+
+        assert_eq!(keep, 0b0110);
+        assert_eq!(mock::mask(), 0b1010);
+    }
+
+    #[cfg(feature = "timer")]
+    #[test]
+    fn coalesce_handles_each_event_of_a_burst_once() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        let mut handled = 0;
+        let events = coalesce(5, 100, || {
+            handled += 1;
+            // Servicing the peripheral clears the line, and the next event
+            // of the burst arrives within the window
+            mock::clear(1 << 5);
+            if handled < 3 {
+                mock::advance(40);
+                mock::raise(1 << 5);
+            }
+        });
+
+        assert_eq!(events, 3);
+        assert_eq!(handled, 3);
+        assert_eq!(mock::pending(), 1 << TIMER_IRQ);
+        assert_eq!(mock::expiries(), 1);
+    }
+
+    #[cfg(feature = "timer")]
+    #[test]
+    fn coalesce_ends_after_one_event_if_the_timer_is_pending() {
+        mock::reset();
+        mock::raise(1 << TIMER_IRQ);
+
+        let mut handled = 0;
+        let events = coalesce(5, 100, || {
+            handled += 1;
+        });
+
+        assert_eq!((events, handled), (1, 1));
+    }
+
+    #[test]
+    fn shutdown_leaves_the_core_quiescent() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        run_deferred();
+        ran();
+
+        unsafe {
+            maskirq(!((1 << TIMER_IRQ) | (1 << 3)));
+            #[cfg(feature = "timer")]
+            timer(5);
+        }
+        defer(first).unwrap();
+
+        shutdown();
+
+        assert_eq!(mock::mask(), 0xffff_ffff);
+        assert_eq!(mock::counter(), 0);
+        assert_eq!(ran(), [1]);
+
+        // The queue is empty and the disarmed timer never goes pending
+        run_deferred();
+        mock::advance(100);
+        assert_eq!(ran(), []);
+        assert_eq!(mock::pending(), 0);
+    }
+
+    #[test]
+    fn wait_for_irq_returns_on_a_pending_line_and_services_only_it() {
+        static TABLE: HandlerTable = HandlerTable::new()
+            .with(4, record_line)
+            .with(6, record_line);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        ran();
+
+        let before = swap_table(&TABLE);
+
+        // Line 6 stays masked, line 4 arrives just before the wait
+        unsafe {
+            maskirq(!(1 << 4));
+        }
+        mock::raise((1 << 4) | (1 << 6));
+        mock::on_interrupt(Some(dispatch));
+
+        wait_for_irq(IrqLine::External(4));
+
+        assert_eq!(ran(), [4]);
+        assert_eq!(mock::pending(), 1 << 6);
+        assert_eq!(mock::mask(), !(1 << 4));
+
+        // The wakeup reports every pending line
+        mock::on_interrupt(None);
+        mock::raise(1 << 4);
+        assert_eq!(wait_for(1 << 4), (1 << 4) | (1 << 6));
+
+        swap_table(before);
+    }
+
+    #[cfg(feature = "interrupts-qregs")]
+    #[test]
+    fn a_posted_message_is_taken_once_by_the_handler() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        static TAKEN: AtomicU32 = AtomicU32::new(0);
+
+        fn take(context: &HandlerContext) {
+            TAKEN.store(context.take_message(), Ordering::Relaxed);
+        }
+
+        static TABLE: HandlerTable = HandlerTable::new().with(3, take);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+
+        let before = swap_table(&TABLE);
+
+        // The last writer wins
+        post_message(0x1234);
+        post_message(0xdead_beef);
+        dispatch(1 << 3);
+        assert_eq!(TAKEN.load(Ordering::Relaxed), 0xdead_beef);
+
+        // The slot is empty once taken
+        dispatch(1 << 3);
+        assert_eq!(TAKEN.load(Ordering::Relaxed), 0);
+
+        swap_table(before);
+    }
+
+    #[cfg(feature = "interrupts-qregs")]
+    #[test]
+    fn with_scratch_restores_q2_and_q3() {
+        mock::reset();
+
+        unsafe {
+            setq2(0x22);
+            setq3(0x33);
+        }
+
+        let context = HandlerContext {
+            line: 3,
+            pending: 1 << 3,
+        };
+        let sum = context.with_scratch(|regs| {
+            assert_eq!((regs.q2(), regs.q3()), (0x22, 0x33));
+
+            regs.set_q2(0x100);
+            regs.set_q3(0x200);
+            assert_eq!((regs.q2(), regs.q3()), (0x100, 0x200));
+
+            regs.q2() + regs.q3()
+        });
+
+        assert_eq!(sum, 0x300);
+        assert_eq!(unsafe { (getq2(), getq3()) }, (0x22, 0x33));
+    }
+
+    #[test]
+    fn in_interrupt_is_only_true_inside_vector_dispatch() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        static INSIDE: AtomicBool = AtomicBool::new(false);
+
+        fn check(_: &HandlerContext) {
+            INSIDE.store(in_interrupt(), Ordering::Relaxed);
+        }
+
+        static TABLE: HandlerTable = HandlerTable::new().with(4, check);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+
+        assert!(!in_interrupt());
+
+        __vector_dispatch(1 << 4, |line| if line == 4 { Some(check) } else { None });
+
+        assert!(INSIDE.load(Ordering::Relaxed));
+        assert!(!in_interrupt());
+
+        // Handlers reached through `dispatch` are not told
+        let before = swap_table(&TABLE);
+        dispatch(1 << 4);
+        swap_table(before);
+
+        assert!(!INSIDE.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn a_critical_guard_restores_the_mask_on_an_early_return() {
+        fn parse(digit: u8) -> Result<u8, ()> {
+            let _guard = unsafe { enter() };
+            assert_eq!(mock::mask(), 0xffff_ffff);
+
+            let value = char::from(digit).to_digit(10).ok_or(())?;
+
+            Ok(value as u8)
+        }
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        unsafe {
+            maskirq(0b1001);
+        }
+
+        assert_eq!(parse(b'x'), Err(()));
+        assert_eq!(mock::mask(), 0b1001);
+
+        assert_eq!(parse(b'7'), Ok(7));
+        assert_eq!(mock::mask(), 0b1001);
+    }
+
+    #[test]
+    fn mmio_modify_does_not_lose_a_handler_write() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        static REGISTER: AtomicU32 = AtomicU32::new(0);
+
+        fn set_bit_1(_: &HandlerContext) {
+            unsafe { mmio_modify(REGISTER.as_ptr(), |v| v | 0b10) };
+        }
+
+        static TABLE: HandlerTable = HandlerTable::new().with(5, set_bit_1);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        REGISTER.store(0, Ordering::Relaxed);
+
+        let before = swap_table(&TABLE);
+        unsafe {
+            maskirq(!(1 << 5));
+        }
+        mock::on_interrupt(Some(dispatch));
+
+        // The handler's line goes pending between the read and the write,
+        // and only runs once the write is done
+        unsafe {
+            mmio_modify(REGISTER.as_ptr(), |v| {
+                mock::raise(1 << 5);
+                assert_eq!(REGISTER.load(Ordering::Relaxed), 0);
+
+                v | 0b01
+            });
+        }
+
+        assert_eq!(REGISTER.load(Ordering::Relaxed), 0b11);
+        assert_eq!(mock::pending(), 0);
+
+        swap_table(before);
+    }
+
+    #[test]
+    fn cas_retry_loop_keeps_a_handler_update() {
+        std::thread_local! {
+            static COUNT: Cell<u32> = const { Cell::new(0) };
+        }
+
+        fn add_ten(_: &HandlerContext) {
+            COUNT.with(|count| {
+                let mut current = count.get();
+
+                while let Err(actual) = cas(count, current, current + 10) {
+                    current = actual;
+                }
+            });
+        }
+
+        static TABLE: HandlerTable = HandlerTable::new().with(5, add_ten);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+
+        let before = swap_table(&TABLE);
+        unsafe {
+            maskirq(!(1 << 5));
+        }
+        mock::on_interrupt(Some(dispatch));
+
+        let mut attempts = 0;
+        COUNT.with(|count| {
+            let mut current = count.get();
+
+            loop {
+                attempts += 1;
+                // The handler writes the cell between the read and the swap
+                if attempts == 1 {
+                    mock::raise(1 << 5);
+                }
+
+                match cas(count, current, current + 1) {
+                    Ok(_) => break,
+                    Err(actual) => current = actual,
+                }
+            }
+        });
+
+        assert_eq!(attempts, 2);
+        assert_eq!(COUNT.with(Cell::get), 11);
+
+        swap_table(before);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn nested_free_logs_enter_and_exit_in_order() {
+        use std::string::{String, ToString};
+
+        // Records are kept per thread, so other tests don't show up
+        std::thread_local! {
+            static RECORDS: core::cell::RefCell<Vec<String>> =
+                const { core::cell::RefCell::new(Vec::new()) };
+        }
+
+        struct Capture;
+
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                if record.target() == "coucal_rs::interrupt" {
+                    RECORDS.with(|r| r.borrow_mut().push(record.args().to_string()));
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        static CAPTURE: Capture = Capture;
+
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        unsafe {
+            maskirq(0b101);
+        }
+
+        free(|_| free(|_| ()));
+
+        let records = RECORDS.with(|r| r.take());
+        let outer: u32 = records[0]
+            .trim_start_matches("critical section ")
+            .split(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let inner = outer.wrapping_add(1);
+
+        assert_eq!(
+            records,
+            [
+                std::format!("critical section {} enter, mask 0x00000005", outer),
+                std::format!("critical section {} enter, mask 0xffffffff", inner),
+                std::format!("critical section {} exit, mask 0xffffffff", inner),
+                std::format!("critical section {} exit, mask 0x00000005", outer),
+            ]
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn assert_disabled_check_is_skipped_in_a_handler() {
+        fn checked(_: &HandlerContext) {
+            // The entry's critical section, with the interrupted code's mask
+            AssertDisabled::new(unsafe { CriticalSection::new() }).check();
+        }
+
+        let _lock = mock::test_lock();
+        mock::reset();
+        unsafe {
+            maskirq(0);
+        }
+
+        __vector_dispatch(1 << 4, |_| Some(checked));
+
+        assert_eq!(mock::mask(), 0);
+    }
+}
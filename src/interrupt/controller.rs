@@ -0,0 +1,96 @@
+//! Ownership of the interrupt configuration
+//!
+//! The IRQ mask and the timer are global to the core. `Controller` is a
+//! token for them: there is only one, so code that changes the interrupt
+//! configuration through it has to be handed the token, and it is clear from
+//! the types which part of the program owns that configuration.
+//!
+//! Nothing stops other code from calling `asm::maskirq` or the other free
+//! functions of this crate directly. The token only helps if the program
+//! routes its interrupt configuration through it.
+
+use super::{free, Mutex};
+use crate::asm::*;
+use core::cell::Cell;
+
+static TAKEN: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// The owner of the IRQ mask and the timer
+pub struct Controller {
+    _private: (),
+}
+
+impl Controller {
+    /// Returns the controller the first time it is called, then `None`
+    pub fn take() -> Option<Self> {
+        free(|cs| {
+            let taken = TAKEN.borrow(cs);
+
+            if taken.get() {
+                None
+            } else {
+                taken.set(true);
+
+                Some(Controller { _private: () })
+            }
+        })
+    }
+
+    /// Returns the controller without checking whether it has been taken
+    ///
+    /// # Safety
+    ///
+    /// - Using the stolen token alongside another `Controller` defeats the
+    ///   ownership the token stands for. The caller must make sure the two
+    ///   are not used to configure interrupts independently
+    pub unsafe fn steal() -> Self {
+        Controller { _private: () }
+    }
+
+    /// Masks the interrupts in `lines`, leaving the other lines as they are
+    ///
+    /// A `0b1` in `lines` selects the line. Returns the previous mask.
+    pub fn mask(&mut self, lines: u32) -> u32 {
+        unsafe {
+            let old_mask = maskirq(0xffff_ffff);
+            maskirq(old_mask | lines);
+
+            old_mask
+        }
+    }
+
+    /// Unmasks the interrupts in `lines`, leaving the other lines as they are
+    ///
+    /// A `0b1` in `lines` selects the line. Returns the previous mask.
+    ///
+    /// # Safety
+    ///
+    /// - Do not call this function inside an `interrupt::free` critical
+    ///   section
+    pub unsafe fn unmask(&mut self, lines: u32) -> u32 {
+        let old_mask = maskirq(0xffff_ffff);
+        maskirq(old_mask & !lines);
+
+        old_mask
+    }
+
+    /// Blocks until an interrupt is pending and returns the pending bitmask
+    ///
+    /// This is `asm::waitirq`, so masked lines also wake it. If no interrupt
+    /// can become pending this function never returns.
+    pub fn wait(&mut self) -> u32 {
+        unsafe { waitirq() }
+    }
+
+    /// Arms the timer to fire in `cycles` cycles, or stops it if `cycles` is
+    /// zero
+    ///
+    /// Returns the previous counter value, like `asm::timer`.
+    ///
+    /// Note: this function is only available when the `timer` feature is
+    /// enabled.
+    #[cfg(feature = "timer")]
+    pub fn set_timer(&mut self, cycles: u32) -> u32 {
+        unsafe { timer(cycles) }
+    }
+}
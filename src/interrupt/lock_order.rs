@@ -0,0 +1,172 @@
+//! Lock ordering checks
+//!
+//! Firmware that nests `interrupt::free` with other locks (for example a
+//! driver's internal lock) can deadlock if two code paths take the same locks
+//! in a different order. This module tracks a small stack of held lock
+//! levels in debug builds and panics when a lock is acquired out of order.
+//!
+//! Locks must be acquired in descending level order: a lock can only be
+//! acquired while every held lock has the same or a higher level. Each
+//! `interrupt::free` critical section registers itself at `INTERRUPT_LEVEL`,
+//! so locks that may be taken inside a critical section need a lower level
+//! and locks that wrap critical sections need a higher one.
+//!
+//! Interrupt handlers share the stack with the code they interrupted, so a
+//! handler that takes a lock is checked against the locks held at the point
+//! of interruption. This has a false positive: a handler that calls
+//! `interrupt::free`, or anything built on it such as `defer` (which is
+//! meant for handlers), panics if it interrupts main code holding any lock
+//! with a level below `INTERRUPT_LEVEL`, even though the handler runs to
+//! completion before the main code can touch that lock again. Give locks
+//! that are held while such an interrupt can be taken a level of at least
+//! `INTERRUPT_LEVEL`.
+//!
+//! Under the `mock-host` backend each thread has a stack of its own, like it
+//! has its own interrupt mask.
+//!
+//! In release builds every function in this module does nothing.
+
+#[cfg(all(debug_assertions, not(all(feature = "mock-host", not(riscv)))))]
+use super::{CriticalSection, Mutex};
+#[cfg(debug_assertions)]
+use crate::asm::maskirq;
+#[cfg(debug_assertions)]
+use core::cell::RefCell;
+
+/// The level at which `interrupt::free` critical sections are registered
+pub const INTERRUPT_LEVEL: u8 = 0x80;
+
+/// Maximum number of locks that can be held at once
+pub const MAX_DEPTH: usize = 16;
+
+#[cfg(debug_assertions)]
+struct Held {
+    levels: [u8; MAX_DEPTH],
+    depth: usize,
+}
+
+#[cfg(debug_assertions)]
+impl Held {
+    const EMPTY: Held = Held {
+        levels: [0; MAX_DEPTH],
+        depth: 0,
+    };
+}
+
+#[cfg(all(debug_assertions, not(all(feature = "mock-host", not(riscv)))))]
+static HELD: Mutex<RefCell<Held>> = Mutex::new(RefCell::new(Held::EMPTY));
+
+#[cfg(all(debug_assertions, feature = "mock-host", not(riscv)))]
+std::thread_local! {
+    static HELD: RefCell<Held> = const { RefCell::new(Held::EMPTY) };
+}
+
+/// Records that a lock at `level` is being acquired
+///
+/// # Panics
+///
+/// In debug builds this panics if a lock with a lower level is already
+/// held, or if more than `MAX_DEPTH` locks would be held.
+#[inline]
+#[allow(unused_variables)]
+pub fn acquire(level: u8) {
+    #[cfg(debug_assertions)]
+    with_held(|held| {
+        assert!(held.depth < MAX_DEPTH, "lock order stack overflow");
+
+        if held.depth > 0 {
+            let top = held.levels[held.depth - 1];
+
+            assert!(
+                level <= top,
+                "lock order violation: level {} acquired while holding level {}",
+                level,
+                top
+            );
+        }
+
+        held.levels[held.depth] = level;
+        held.depth += 1;
+    });
+}
+
+/// Records that the most recently acquired lock, at `level`, is released
+///
+/// # Panics
+///
+/// In debug builds this panics if `level` is not the level of the most
+/// recently acquired lock that is still held.
+#[inline]
+#[allow(unused_variables)]
+pub fn release(level: u8) {
+    #[cfg(debug_assertions)]
+    with_held(|held| {
+        assert!(
+            held.depth > 0 && held.levels[held.depth - 1] == level,
+            "lock order violation: level {} released out of order",
+            level
+        );
+
+        held.depth -= 1;
+    });
+}
+
+/// Runs `f` on the held lock stack with interrupts masked
+///
+/// This can't use `interrupt::free`, which is itself tracked.
+#[cfg(debug_assertions)]
+fn with_held<F>(f: F)
+where
+    F: FnOnce(&mut Held),
+{
+    unsafe {
+        let mask = maskirq(0xffff_ffff);
+
+        #[cfg(not(all(feature = "mock-host", not(riscv))))]
+        f(&mut HELD.borrow(CriticalSection::new()).borrow_mut());
+        #[cfg(all(feature = "mock-host", not(riscv)))]
+        HELD.with(|held| f(&mut held.borrow_mut()));
+
+        maskirq(mask);
+    }
+}
+
+#[cfg(all(test, debug_assertions, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::interrupt::free;
+    use crate::mock;
+
+    #[test]
+    fn locks_taken_in_descending_order_are_accepted() {
+        mock::reset();
+
+        acquire(INTERRUPT_LEVEL + 1);
+        free(|_| {
+            acquire(0x10);
+            release(0x10);
+        });
+        release(INTERRUPT_LEVEL + 1);
+
+        with_held(|held| assert_eq!(held.depth, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violation: level 128 acquired while holding level 16")]
+    fn free_inside_a_lower_lock_is_flagged() {
+        mock::reset();
+
+        acquire(0x10);
+        free(|_| ());
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violation: level 64 released out of order")]
+    fn release_out_of_order_is_flagged() {
+        mock::reset();
+
+        acquire(0x40);
+        acquire(0x20);
+        release(0x40);
+    }
+}
@@ -0,0 +1,167 @@
+//! Typed interrupt masks
+//!
+//! The PicoRV32 IRQ mask register uses `0b1` for a masked (disabled) line,
+//! the opposite of the "enable" bits found on most interrupt controllers.
+//! `IrqMask` wraps the raw value so that code reads in terms of masked and
+//! unmasked lines instead of bits.
+
+use crate::asm::maskirq;
+use crate::interrupt::pending_lines;
+use core::fmt;
+
+/// An IRQ mask in `maskirq` polarity: a set bit masks the line
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct IrqMask(u32);
+
+impl IrqMask {
+    /// Creates a mask from its raw `maskirq` value
+    pub const fn from_bits(bits: u32) -> Self {
+        IrqMask(bits)
+    }
+
+    /// Returns the raw `maskirq` value
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// A mask with every line masked
+    pub const fn all_masked() -> Self {
+        IrqMask(0xffff_ffff)
+    }
+
+    /// A mask with every line unmasked
+    pub const fn none_masked() -> Self {
+        IrqMask(0)
+    }
+
+    /// Returns `true` if line `nr` is masked
+    ///
+    /// `nr` must be less than 32.
+    pub const fn masked(self, nr: u8) -> bool {
+        self.0 & (1 << nr) != 0
+    }
+
+    /// Returns a copy of this mask with line `nr` masked
+    ///
+    /// `nr` must be less than 32.
+    pub const fn with_masked(self, nr: u8) -> Self {
+        IrqMask(self.0 | (1 << nr))
+    }
+
+    /// Returns a copy of this mask with line `nr` unmasked
+    ///
+    /// `nr` must be less than 32.
+    pub const fn with_unmasked(self, nr: u8) -> Self {
+        IrqMask(self.0 & !(1 << nr))
+    }
+}
+
+impl From<u32> for IrqMask {
+    fn from(bits: u32) -> Self {
+        IrqMask(bits)
+    }
+}
+
+impl From<IrqMask> for u32 {
+    fn from(mask: IrqMask) -> Self {
+        mask.0
+    }
+}
+
+impl IrqMask {
+    /// Writes the masked lines as `{0, 3, 5}`
+    fn fmt_lines(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("{")?;
+
+        for (i, line) in pending_lines(self.0).enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+
+            write!(f, "{}", line)?;
+        }
+
+        f.write_str("}")
+    }
+}
+
+/// Prints the masked lines, for example `masked: {0, 3, 5}`
+impl fmt::Display for IrqMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("masked: ")?;
+        self.fmt_lines(f)
+    }
+}
+
+/// Prints the raw value and the masked lines, for example
+/// `IrqMask(0x00000029, masked: {0, 3, 5})`
+impl fmt::Debug for IrqMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IrqMask({:#010x}, masked: ", self.0)?;
+        self.fmt_lines(f)?;
+        f.write_str(")")
+    }
+}
+
+/// Prints the masked lines, for example `IrqMask{masked: [0, 3, 5]}`
+#[cfg(feature = "defmt")]
+impl defmt::Format for IrqMask {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "IrqMask{{masked: [");
+
+        for (i, line) in pending_lines(self.0).enumerate() {
+            if i != 0 {
+                defmt::write!(f, ", ");
+            }
+
+            defmt::write!(f, "{=u8}", line);
+        }
+
+        defmt::write!(f, "]}}");
+    }
+}
+
+/// Typed companion to `maskirq`
+///
+/// Writes `mask` to the IRQ mask register and returns the previous mask.
+///
+/// # Safety
+///
+/// - Unmasking interrupts inside an `interrupt::free` critical section breaks
+///   the guarantees of that critical section
+#[inline]
+pub unsafe fn maskirq_typed(mask: IrqMask) -> IrqMask {
+    IrqMask(maskirq(mask.0))
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn display_lists_the_masked_lines() {
+        assert_eq!(format!("{}", IrqMask::none_masked()), "masked: {}");
+        assert_eq!(format!("{}", IrqMask::from_bits(1 << 31)), "masked: {31}");
+        assert_eq!(
+            format!("{}", IrqMask::from_bits(0b10_1001)),
+            "masked: {0, 3, 5}"
+        );
+    }
+
+    #[test]
+    fn debug_adds_the_raw_value() {
+        assert_eq!(
+            format!("{:?}", IrqMask::none_masked()),
+            "IrqMask(0x00000000, masked: {})"
+        );
+        assert_eq!(
+            format!("{:?}", IrqMask::from_bits(0b100)),
+            "IrqMask(0x00000004, masked: {2})"
+        );
+        assert_eq!(
+            format!("{:?}", IrqMask::from_bits(0b10_1001)),
+            "IrqMask(0x00000029, masked: {0, 3, 5})"
+        );
+    }
+}
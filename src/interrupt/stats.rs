@@ -0,0 +1,101 @@
+//! Interrupt statistics
+//!
+//! With the `stats` feature enabled, `interrupt::dispatch`,
+//! `Dispatcher::dispatch` and the entry generated by `interrupt_vector!`
+//! count every line they find pending, whether or not it has a handler. That
+//! answers "is this interrupt actually firing?" in the field, without a
+//! debugger attached.
+//!
+//! The counters wrap around on overflow.
+//!
+//! Note: this module is only available when the `stats` feature is enabled.
+
+use super::{free, CriticalSection, Mutex};
+use crate::asm::maskirq;
+use core::cell::Cell;
+
+/// Per-line counts of serviced interrupts
+pub struct Stats {
+    counts: Mutex<[Cell<u32>; 32]>,
+}
+
+static STATS: Stats = Stats {
+    counts: Mutex::new([const { Cell::new(0) }; 32]),
+};
+
+/// Returns the statistics collected by the dispatch functions
+pub fn stats() -> &'static Stats {
+    &STATS
+}
+
+impl Stats {
+    /// Returns the number of times line `nr` has been serviced
+    ///
+    /// # Panics
+    ///
+    /// Panics if `nr` is not less than 32.
+    pub fn count(&self, nr: u8) -> u32 {
+        free(|cs| self.counts.borrow(cs)[nr as usize].get())
+    }
+
+    /// Sets every count back to zero
+    pub fn reset(&self) {
+        free(|cs| {
+            for count in self.counts.borrow(cs) {
+                count.set(0);
+            }
+        });
+    }
+}
+
+/// Counts a dispatch of `line`
+#[inline]
+pub(crate) fn record(line: u8) {
+    // Count without `free`, so the dispatch functions don't trace a critical
+    // section for every line.
+    unsafe {
+        let mask = maskirq(0xffff_ffff);
+        let cs = CriticalSection::new();
+        let count = &STATS.counts.borrow(cs)[line as usize];
+        count.set(count.get().wrapping_add(1));
+        maskirq(mask);
+    }
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::interrupt::dispatch;
+    use crate::mock;
+
+    #[test]
+    fn dispatch_counts_each_pending_line_until_reset() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        stats().reset();
+
+        dispatch(0b1010);
+        dispatch(0b1000);
+
+        assert_eq!(stats().count(0), 0);
+        assert_eq!(stats().count(1), 1);
+        assert_eq!(stats().count(3), 2);
+
+        stats().reset();
+
+        assert_eq!(stats().count(1), 0);
+        assert_eq!(stats().count(3), 0);
+    }
+
+    #[test]
+    fn counts_wrap_around() {
+        let _lock = mock::test_lock();
+        mock::reset();
+        stats().reset();
+
+        free(|cs| STATS.counts.borrow(cs)[5].set(u32::MAX));
+        record(5);
+
+        assert_eq!(stats().count(5), 0);
+    }
+}
@@ -0,0 +1,72 @@
+//! Interrupt event tracing
+//!
+//! With the `trace` feature enabled, interrupt entry and exit (as seen by
+//! `interrupt::dispatch` and the `interrupt_vector!` entry) and critical
+//! section entry and exit are reported to a user supplied `TraceSink`. This
+//! gives a timeline of interrupt activity for performance analysis, much like
+//! the ARM ITM.
+//!
+//! The crate has no clock of its own, since the PicoRV32 cycle counters are
+//! optional, so timestamping events is left to the sink.
+//!
+//! Critical section events are recorded with every interrupt masked, so the
+//! sink must not rely on interrupts (for example a memory ring or a polled
+//! RTT channel are fine).
+
+use super::{CriticalSection, Mutex};
+use crate::asm::maskirq;
+use core::cell::Cell;
+
+/// An interrupt event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// The handler for `line` is about to run
+    InterruptEnter { line: u8 },
+    /// The handler for `line` has returned
+    InterruptExit { line: u8 },
+    /// A critical section has been entered
+    CriticalEnter,
+    /// A critical section is about to be left
+    CriticalExit,
+}
+
+/// A destination for trace events
+pub trait TraceSink: Sync {
+    /// Records `event`
+    ///
+    /// This is called on the hot path of every interrupt and critical
+    /// section, so it should be quick.
+    fn record(&self, event: TraceEvent);
+}
+
+static SINK: Mutex<Cell<Option<&'static dyn TraceSink>>> = Mutex::new(Cell::new(None));
+
+/// Sends trace events to `sink`, or stops tracing if `sink` is `None`
+pub fn set_sink(sink: Option<&'static dyn TraceSink>) {
+    super::free(|cs| SINK.borrow(cs).set(sink));
+}
+
+/// Records `event` with a critical section already held
+#[inline]
+pub(crate) fn record_in(cs: CriticalSection, event: TraceEvent) {
+    if let Some(sink) = SINK.borrow(cs).get() {
+        sink.record(event);
+    }
+}
+
+/// Records `event`
+#[inline]
+pub(crate) fn record(event: TraceEvent) {
+    // Look the sink up without `free`, which would trace itself.
+    let sink = unsafe {
+        let mask = maskirq(0xffff_ffff);
+        let sink = SINK.borrow(CriticalSection::new()).get();
+        maskirq(mask);
+
+        sink
+    };
+
+    if let Some(sink) = sink {
+        sink.record(event);
+    }
+}
@@ -0,0 +1,101 @@
+//! Waiting for interrupts from async code
+//!
+//! `wait_irq_async` returns a future that completes the next time the
+//! handler of a line calls `wake` for it. Each line keeps a count of the
+//! `wake` calls and the waker of the task waiting on it. A future completes
+//! once the count has moved on from the value it saw when it was created, so
+//! an interrupt that fires between creating the future and polling it is not
+//! lost, and one that fired before it was created is not reported.
+//!
+//! The per-line table is only accessed inside `interrupt::free`. Wakers are
+//! woken after leaving the critical section, so the executor's wake code
+//! runs with the mask of the caller of `wake`.
+//!
+//! Note: this module is only available when the `async` feature is enabled.
+
+use super::{free, Mutex, Nr};
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+struct Line {
+    wakes: u32,
+    waker: Option<Waker>,
+}
+
+static LINES: Mutex<RefCell<[Line; 32]>> = Mutex::new(RefCell::new(
+    [const {
+        Line {
+            wakes: 0,
+            waker: None,
+        }
+    }; 32],
+));
+
+/// Future returned by `wait_irq_async`
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct IrqFuture {
+    line: u8,
+    wakes: u32,
+}
+
+impl Future for IrqFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        free(|cs| {
+            let mut lines = LINES.borrow(cs).borrow_mut();
+            let line = &mut lines[self.line as usize];
+
+            if line.wakes != self.wakes {
+                return Poll::Ready(());
+            }
+
+            match line.waker {
+                Some(ref waker) if waker.will_wake(cx.waker()) => {}
+                _ => line.waker = Some(cx.waker().clone()),
+            }
+
+            Poll::Pending
+        })
+    }
+}
+
+/// Returns a future that completes the next time `wake` is called for `irq`
+///
+/// Only one task can wait on a line at a time: polling a second future for
+/// the same line replaces the waker of the first.
+///
+/// `irq.nr()` must be less than 32.
+pub fn wait_irq_async<I: Nr>(irq: I) -> IrqFuture {
+    let line = irq.nr();
+    debug_assert!(line < 32, "IRQ number {} out of range", line);
+
+    let wakes = free(|cs| LINES.borrow(cs).borrow()[line as usize].wakes);
+
+    IrqFuture { line, wakes }
+}
+
+/// Completes the futures waiting on line `nr` and wakes their task
+///
+/// Call this from the handler of the line once the peripheral has been
+/// serviced.
+///
+/// # Panics
+///
+/// Panics if `nr` is not less than 32.
+pub fn wake(nr: u8) {
+    let waker = free(|cs| {
+        let mut lines = LINES.borrow(cs).borrow_mut();
+        let line = &mut lines[nr as usize];
+
+        line.wakes = line.wakes.wrapping_add(1);
+        line.waker.take()
+    });
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
@@ -2,8 +2,9 @@
 //!
 //! # Minimum Supported Rust Version (MSRV)
 //!
-//! This crate is guaranteed to compile on stable Rust 1.32 and up. It *might*
-//! compile with older versions but that may change in any new patch release.
+//! This crate is guaranteed to compile on stable Rust 1.82 and up, the first
+//! release with `const` operands in `asm!`. It *might* compile with older
+//! versions but that may change in any new patch release.
 //!
 //! # Features
 //!
@@ -11,11 +12,100 @@
 //!
 //! - PicoRV32's interrupt manipulation mechanisms.
 //! - Wrappers around assembly instructions such as `waitirq`.
+//! - Blocking delays built on the PicoRV32 timer.
+//! - With the `counters` feature, the cycle counter and an `Instant` and
+//!   `Duration` clock built on it (see the `time` module).
+//! - With the `embedded-hal` feature, `embedded-hal` trait implementations
+//!   built on the timer.
+//! - With the `async` feature, futures that complete on an interrupt (see
+//!   `interrupt::wait_irq_async`).
+//! - With the `atomic-polyfill` feature, atomic integers for cores without
+//!   the A extension (see the `atomic` module).
+//! - With the `defmt` feature, `defmt::Format` for `IrqMask` and
+//!   `TrapCause`.
+//! - With the `semihosting` feature, debug output through a semihosting
+//!   debugger for early bring-up (see the `debug` module).
+//! - With the `stats` feature, per-line counts of serviced interrupts (see
+//!   `interrupt::stats`).
+//! - With the `mock-host` feature, a simulation of the interrupt state for
+//!   unit tests on the host (see the `mock` module).
+//! - With the `critical-section` feature, an implementation of the
+//!   `critical-section` crate so that crates depending on it work on
+//!   PicoRV32.
+//! - With the `macros` feature, the `#[interrupt]` attribute, which turns a
+//!   function into the interrupt entry.
+//!
+//! # Interrupt vector placement
+//!
+//! The core jumps to `PROGADDR_IRQ` when an interrupt is taken, so the
+//! interrupt entry code must be linked at that address. The build script
+//! generates a `coucal-irq.x` linker script fragment which places the
+//! `.irq_vector` section at the vector address. Include it from your own
+//! linker script:
+//!
+//! ```text
+//! INCLUDE coucal-irq.x
+//! ```
+//!
+//! and put the entry code in the `.irq_vector` section, or have the
+//! `interrupt_vector!` macro generate it. The address is read from the
+//! `COUCAL_VECTOR_ADDR` environment variable at build time (decimal or
+//! `0x`-prefixed hexadecimal) and defaults to `0x10`, the PicoRV32 default.
+//!
+//! # Custom opcode
+//!
+//! The interrupt instructions use the `CUSTOM_0` opcode by default. For a
+//! core whose integration moved them to another custom opcode, set the
+//! `COUCAL_CUSTOM_OPCODE` environment variable at build time to the number
+//! of that opcode, `0` to `3` (`custom0` to `custom3` also work). Every
+//! instruction wrapper, `insn_r!` and `Instruction::encode`/`decode` follow
+//! the selected opcode, which is available as `asm::OPCODE`.
 
 #![no_std]
 #![deny(warnings)]
 
 extern crate bare_metal;
+#[cfg(all(feature = "mock-host", not(riscv)))]
+extern crate std;
+
+#[macro_use]
+mod macros;
 
 pub mod asm;
+#[cfg(feature = "atomic-polyfill")]
+pub mod atomic;
+#[cfg(feature = "critical-section")]
+mod critical_section;
+#[cfg(feature = "semihosting")]
+pub mod debug;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
 pub mod interrupt;
+#[cfg(all(feature = "mock-host", not(riscv)))]
+pub mod mock;
+pub mod sync;
+#[cfg(feature = "counters")]
+pub mod time;
+pub mod timer;
+
+/// Makes `fn(u32)` the interrupt handler of the program
+///
+/// See the `coucal-rt-macros` crate for the details. A handler with any other
+/// signature is rejected:
+///
+/// ```compile_fail
+/// #[coucal_rs::interrupt]
+/// fn on_irq() {}
+/// ```
+///
+/// ```compile_fail
+/// #[coucal_rs::interrupt]
+/// fn on_irq(irqs: u32) -> u32 {
+///     irqs
+/// }
+/// ```
+///
+/// Note: this attribute is only available when the `macros` feature is
+/// enabled.
+#[cfg(feature = "macros")]
+pub use coucal_rt_macros::interrupt;
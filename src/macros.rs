@@ -0,0 +1,493 @@
+/// Declares the board's peripheral interrupt wiring
+///
+/// Each entry gives a peripheral name, the base address of its registers and
+/// the IRQ line it raises:
+///
+/// ```ignore
+/// peripheral_irq_map! {
+///     UART @ 0x4000_0000 => line 3,
+///     SPI @ 0x4000_1000 => line 4,
+/// }
+/// ```
+///
+/// For each entry this generates a unit struct with `ADDRESS` and `IRQ`
+/// associated constants, which implements `Nr` with the IRQ line. It also
+/// generates `irq_line_for(addr: u32) -> Option<u8>`, a `match` returning the
+/// IRQ line of the peripheral based at `addr`.
+///
+/// The map is checked at compile time: every line must be less than 32, and
+/// no two peripherals may share a base address or an IRQ line.
+///
+/// ```compile_fail,E0080
+/// coucal_rs::peripheral_irq_map! {
+///     UART @ 0x4000_0000 => line 32,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail,E0080
+/// coucal_rs::peripheral_irq_map! {
+///     UART @ 0x4000_0000 => line 3,
+///     SPI @ 0x4000_1000 => line 3,
+/// }
+/// # fn main() {}
+/// ```
+#[macro_export]
+macro_rules! peripheral_irq_map {
+    ($($name:ident @ $addr:literal => line $line:literal),+ $(,)?) => {
+        const _: () = {
+            $(
+                assert!(
+                    $line < 32,
+                    concat!("IRQ line of `", stringify!($name), "` is not less than 32")
+                );
+            )+
+            assert!(
+                $crate::interrupt::__all_distinct(&[$($addr),+]),
+                "two peripherals share a base address"
+            );
+            assert!(
+                $crate::interrupt::__all_distinct(&[$($line),+]),
+                "two peripherals share an IRQ line"
+            );
+        };
+
+        $(
+            #[allow(clippy::upper_case_acronyms)]
+            #[derive(Clone, Copy, Debug)]
+            pub struct $name;
+
+            impl $name {
+                /// Base address of the peripheral's registers
+                pub const ADDRESS: u32 = $addr;
+                /// IRQ line raised by the peripheral
+                pub const IRQ: u8 = $line;
+            }
+
+            unsafe impl $crate::interrupt::Nr for $name {
+                fn nr(&self) -> u8 {
+                    $line
+                }
+            }
+        )+
+
+        /// Returns the IRQ line of the peripheral based at `addr`
+        #[allow(dead_code)]
+        pub fn irq_line_for(addr: u32) -> Option<u8> {
+            match addr {
+                $($addr => Some($line),)+
+                _ => None,
+            }
+        }
+    };
+}
+
+/// Emits a PicoRV32 `CUSTOM_0` R-type instruction
+///
+/// The interrupt instructions of PicoRV32 are all R-type instructions on the
+/// `CUSTOM_0` opcode (or the one selected by `COUCAL_CUSTOM_OPCODE`), told
+/// apart by `func7`. Custom builds of the core can add their own instructions
+/// in the same space, and this macro emits them without having to write the
+/// `.insn` directive by hand:
+///
+/// ```ignore
+/// let ret: u32;
+///
+/// // func7, rd, rs1
+/// insn_r!(0b0100000, out(reg) ret, in(reg) arg);
+/// insn_r!(0b0100001, zero, in(reg) arg);
+/// ```
+///
+/// The `func7` values of the standard instructions are available as
+/// `asm::FUNC7_GETQ` and so on, so `insn_r!(FUNC7_WAITIRQ, out(reg) ret,
+/// zero)` is `waitirq`.
+///
+/// The instruction is laid out as:
+///
+/// | Bits    | Field    | Value                                   |
+/// |---------|----------|-----------------------------------------|
+/// | 31 - 25 | `func7`  | first macro argument                    |
+/// | 24 - 20 | `rs2`    | always `x0`                             |
+/// | 19 - 15 | `rs1`    | third macro argument                    |
+/// | 14 - 12 | `func3`  | always `0`, PicoRV32 ignores it         |
+/// | 11 - 7  | `rd`     | second macro argument                   |
+/// | 6 - 0   | `opcode` | `asm::OPCODE`, normally `CUSTOM_0`       |
+///
+/// `rd` is either `out(reg) place` or `zero`, and `rs1` is either
+/// `in(reg) expr` or `zero`. The registers are allocated by the compiler.
+/// The expansion is an `asm!` invocation, so it must be used in an `unsafe`
+/// block and only compiles for RISC-V targets.
+#[macro_export]
+macro_rules! insn_r {
+    ($func7:expr, out($rd:tt) $ret:expr, in($rs1:tt) $val:expr) => {
+        ::core::arch::asm!(
+            ".insn r {opcode}, 0, {func7}, {rd}, {rs1}, zero",
+            opcode = const $crate::asm::OPCODE,
+            func7 = const $func7,
+            rd = out($rd) $ret,
+            rs1 = in($rs1) $val,
+        )
+    };
+    ($func7:expr, out($rd:tt) $ret:expr, zero) => {
+        ::core::arch::asm!(
+            ".insn r {opcode}, 0, {func7}, {rd}, zero, zero",
+            opcode = const $crate::asm::OPCODE,
+            func7 = const $func7,
+            rd = out($rd) $ret,
+        )
+    };
+    ($func7:expr, zero, in($rs1:tt) $val:expr) => {
+        ::core::arch::asm!(
+            ".insn r {opcode}, 0, {func7}, zero, {rs1}, zero",
+            opcode = const $crate::asm::OPCODE,
+            func7 = const $func7,
+            rs1 = in($rs1) $val,
+        )
+    };
+    ($func7:expr, zero, zero) => {
+        ::core::arch::asm!(
+            ".insn r {opcode}, 0, {func7}, zero, zero, zero",
+            opcode = const $crate::asm::OPCODE,
+            func7 = const $func7,
+        )
+    };
+}
+
+/// Generates the interrupt entry point and dispatches to the given handlers
+///
+/// Each entry gives an IRQ line, as a literal or a path to a constant, and
+/// the `Handler` for it:
+///
+/// ```ignore
+/// interrupt_vector! {
+///     interrupt::TIMER_IRQ => on_timer,
+///     3 => on_uart,
+/// }
+///
+/// fn on_timer(_: &HandlerContext) { /* .. */ }
+/// fn on_uart(_: &HandlerContext) { /* .. */ }
+/// ```
+///
+/// This emits the code the core jumps to when it takes an interrupt, in the
+/// `.irq_vector` section (see "Interrupt vector placement" in the crate
+/// documentation). It:
+///
+/// 1. saves the registers a Rust function may clobber on the interrupted
+///    code's stack: `ra`, `t0`-`t6` and `a0`-`a7` (`t0`-`t2` and `a0`-`a5`
+///    on RV32E),
+/// 2. reads the bitmask of pending IRQs from `q1` (`x4` when the
+///    `interrupts-qregs` feature is disabled),
+/// 3. calls the fast handler installed with `interrupt::set_fast_handler`
+///    if its line is pending, then the handler of each other line set in
+///    it, in ascending order, and `DefaultHandler` for the lines without
+///    one,
+/// 4. restores the saved registers and returns with `retirq`.
+///
+/// The other registers survive because the handlers follow the calling
+/// convention and the compiler never allocates `gp` and `tp`. That leaves
+/// two requirements on the rest of the program:
+///
+/// - The stack pointer must be valid, with room for the saved registers and
+///   the handlers' own frames, wherever an interrupt can be taken.
+/// - Without q-registers the core saves the return address in `x3` (`gp`)
+///   and the pending bitmask in `x4` (`tp`), so the program must not use
+///   those registers for anything else.
+///
+/// `DefaultHandler` stops the core unless the application defines its own:
+///
+/// ```ignore
+/// #[no_mangle]
+/// extern "C" fn DefaultHandler(line: u8) {
+///     log::warn!("unexpected interrupt on line {}", line);
+/// }
+/// ```
+///
+/// The handlers run with interrupts disabled, as the core doesn't take
+/// another interrupt until `retirq`, and `interrupt::in_interrupt` returns
+/// `true` while they do. The macro can only be used once in a program.
+#[macro_export]
+macro_rules! interrupt_vector {
+    ($($line:pat => $handler:expr),* $(,)*) => {
+        #[allow(dead_code)]
+        extern "C" fn __coucal_irq_dispatch(pending: u32) {
+            let pending = $crate::interrupt::__vector_fast(pending);
+
+            if pending == 0 {
+                return;
+            }
+
+            $crate::interrupt::__vector_dispatch(pending, |line| match line {
+                $($line => Some($handler as $crate::interrupt::Handler),)*
+                _ => None,
+            });
+        }
+
+        $crate::__irq_entry!(__coucal_irq_dispatch);
+    };
+}
+
+/// Emits the `.irq_vector` entry calling `$dispatch` with the pending IRQs
+#[doc(hidden)]
+#[cfg(riscv)]
+#[macro_export]
+macro_rules! __irq_entry {
+    ($dispatch:ident) => {
+        ::core::arch::global_asm!(
+            concat!(
+                ".section .irq_vector, \"ax\", @progbits\n",
+                $crate::__irq_save!(),
+                $crate::__irq_pending!(),
+                "call {dispatch}\n",
+                $crate::__irq_restore!(),
+                ".insn r {opcode}, 0, {retirq}, zero, zero, zero\n",
+            ),
+            dispatch = sym $dispatch,
+            opcode = const $crate::asm::OPCODE,
+            retirq = const $crate::asm::FUNC7_RETIRQ,
+        );
+    };
+}
+
+/// There is no interrupt entry on the host
+#[doc(hidden)]
+#[cfg(not(riscv))]
+#[macro_export]
+macro_rules! __irq_entry {
+    ($dispatch:ident) => {};
+}
+
+/// Reads the pending IRQs into `a0`: `getq a0, q1`
+#[doc(hidden)]
+#[cfg(all(riscv, feature = "interrupts-qregs"))]
+#[macro_export]
+macro_rules! __irq_pending {
+    () => {
+        ".insn r {opcode}, 0, 0, a0, x1, zero\n"
+    };
+}
+
+/// Reads the pending IRQs into `a0` from `x4`, where the core puts them
+/// without q-registers
+#[doc(hidden)]
+#[cfg(all(riscv, not(feature = "interrupts-qregs")))]
+#[macro_export]
+macro_rules! __irq_pending {
+    () => {
+        "mv a0, x4\n"
+    };
+}
+
+/// Saves the caller-saved registers on the stack
+#[doc(hidden)]
+#[cfg(all(riscv32, not(target_feature = "e")))]
+#[macro_export]
+macro_rules! __irq_save {
+    () => {
+        "addi sp, sp, -64
+        sw ra, 0(sp)
+        sw t0, 4(sp)
+        sw t1, 8(sp)
+        sw t2, 12(sp)
+        sw a0, 16(sp)
+        sw a1, 20(sp)
+        sw a2, 24(sp)
+        sw a3, 28(sp)
+        sw a4, 32(sp)
+        sw a5, 36(sp)
+        sw a6, 40(sp)
+        sw a7, 44(sp)
+        sw t3, 48(sp)
+        sw t4, 52(sp)
+        sw t5, 56(sp)
+        sw t6, 60(sp)\n"
+    };
+}
+
+/// Restores the registers saved by `__irq_save!`
+#[doc(hidden)]
+#[cfg(all(riscv32, not(target_feature = "e")))]
+#[macro_export]
+macro_rules! __irq_restore {
+    () => {
+        "lw ra, 0(sp)
+        lw t0, 4(sp)
+        lw t1, 8(sp)
+        lw t2, 12(sp)
+        lw a0, 16(sp)
+        lw a1, 20(sp)
+        lw a2, 24(sp)
+        lw a3, 28(sp)
+        lw a4, 32(sp)
+        lw a5, 36(sp)
+        lw a6, 40(sp)
+        lw a7, 44(sp)
+        lw t3, 48(sp)
+        lw t4, 52(sp)
+        lw t5, 56(sp)
+        lw t6, 60(sp)
+        addi sp, sp, 64\n"
+    };
+}
+
+/// Saves the caller-saved registers on the stack
+#[doc(hidden)]
+#[cfg(all(riscv32, target_feature = "e"))]
+#[macro_export]
+macro_rules! __irq_save {
+    () => {
+        "addi sp, sp, -40
+        sw ra, 0(sp)
+        sw t0, 4(sp)
+        sw t1, 8(sp)
+        sw t2, 12(sp)
+        sw a0, 16(sp)
+        sw a1, 20(sp)
+        sw a2, 24(sp)
+        sw a3, 28(sp)
+        sw a4, 32(sp)
+        sw a5, 36(sp)\n"
+    };
+}
+
+/// Restores the registers saved by `__irq_save!`
+#[doc(hidden)]
+#[cfg(all(riscv32, target_feature = "e"))]
+#[macro_export]
+macro_rules! __irq_restore {
+    () => {
+        "lw ra, 0(sp)
+        lw t0, 4(sp)
+        lw t1, 8(sp)
+        lw t2, 12(sp)
+        lw a0, 16(sp)
+        lw a1, 20(sp)
+        lw a2, 24(sp)
+        lw a3, 28(sp)
+        lw a4, 32(sp)
+        lw a5, 36(sp)
+        addi sp, sp, 40\n"
+    };
+}
+
+/// Saves the caller-saved registers on the stack
+#[doc(hidden)]
+#[cfg(riscv64)]
+#[macro_export]
+macro_rules! __irq_save {
+    () => {
+        "addi sp, sp, -128
+        sd ra, 0(sp)
+        sd t0, 8(sp)
+        sd t1, 16(sp)
+        sd t2, 24(sp)
+        sd a0, 32(sp)
+        sd a1, 40(sp)
+        sd a2, 48(sp)
+        sd a3, 56(sp)
+        sd a4, 64(sp)
+        sd a5, 72(sp)
+        sd a6, 80(sp)
+        sd a7, 88(sp)
+        sd t3, 96(sp)
+        sd t4, 104(sp)
+        sd t5, 112(sp)
+        sd t6, 120(sp)\n"
+    };
+}
+
+/// Restores the registers saved by `__irq_save!`
+#[doc(hidden)]
+#[cfg(riscv64)]
+#[macro_export]
+macro_rules! __irq_restore {
+    () => {
+        "ld ra, 0(sp)
+        ld t0, 8(sp)
+        ld t1, 16(sp)
+        ld t2, 24(sp)
+        ld a0, 32(sp)
+        ld a1, 40(sp)
+        ld a2, 48(sp)
+        ld a3, 56(sp)
+        ld a4, 64(sp)
+        ld a5, 72(sp)
+        ld a6, 80(sp)
+        ld a7, 88(sp)
+        ld t3, 96(sp)
+        ld t4, 104(sp)
+        ld t5, 112(sp)
+        ld t6, 120(sp)
+        addi sp, sp, 128\n"
+    };
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use crate::interrupt::Nr;
+
+    peripheral_irq_map! {
+        UART @ 0x4000_0000 => line 3,
+        SPI @ 0x4000_1000 => line 4,
+        GPIO @ 0x4000_2000 => line 31,
+    }
+
+    #[test]
+    fn peripheral_constants_match_the_map() {
+        assert_eq!((UART::ADDRESS, UART::IRQ, UART.nr()), (0x4000_0000, 3, 3));
+        assert_eq!((SPI::ADDRESS, SPI::IRQ, SPI.nr()), (0x4000_1000, 4, 4));
+        assert_eq!((GPIO::ADDRESS, GPIO::IRQ, GPIO.nr()), (0x4000_2000, 31, 31));
+    }
+
+    #[test]
+    fn irq_line_for_agrees_with_the_constants() {
+        assert_eq!(irq_line_for(UART::ADDRESS), Some(UART::IRQ));
+        assert_eq!(irq_line_for(SPI::ADDRESS), Some(SPI::IRQ));
+        assert_eq!(irq_line_for(GPIO::ADDRESS), Some(GPIO::IRQ));
+        assert_eq!(irq_line_for(0x4000_0004), None);
+    }
+
+    #[test]
+    fn the_fast_handler_bypasses_the_vector() {
+        use crate::interrupt::{self, HandlerContext};
+        use std::sync::Mutex;
+        use std::vec::Vec;
+
+        static RAN: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+        fn ran() -> Vec<u8> {
+            core::mem::take(&mut *RAN.lock().unwrap())
+        }
+
+        fn fast() {
+            assert!(interrupt::in_interrupt());
+            RAN.lock().unwrap().push(50);
+        }
+
+        fn slow(context: &HandlerContext) {
+            RAN.lock().unwrap().push(context.line());
+        }
+
+        interrupt_vector! {
+            4 => slow,
+            5 => slow,
+        }
+
+        let _lock = crate::mock::test_lock();
+        crate::mock::reset();
+        ran();
+
+        __coucal_irq_dispatch(1 << 5);
+        assert_eq!(ran(), [5]);
+
+        interrupt::set_fast_handler(5, fast);
+        __coucal_irq_dispatch(1 << 5);
+        assert_eq!(ran(), [50]);
+        __coucal_irq_dispatch((1 << 5) | (1 << 4));
+        assert_eq!(ran(), [50, 4]);
+        assert!(!interrupt::in_interrupt());
+
+        interrupt::clear_fast_handler();
+        __coucal_irq_dispatch(1 << 5);
+        assert_eq!(ran(), [5]);
+    }
+}
@@ -0,0 +1,578 @@
+//! Host simulation of the PicoRV32 interrupt state
+//!
+//! With the `mock-host` feature enabled on a non-RISC-V target, `maskirq`,
+//! `waitirq`, `timer`, `delay_cycles`, `ebreak`, `semihost_write`, the
+//! q-register wrappers and the cycle counter reads in `asm` operate on a
+//! simulated core instead of panicking, so code built on this crate can be
+//! unit tested on the host. The functions of this
+//! module let a test inspect that state, make IRQs pending and move the
+//! timer on.
+//!
+//! The simulated state is thread local, so tests running in parallel do not
+//! see each other's masks. The crate's own statics (the active handler table,
+//! the deferred queue, ...) are still shared by every thread, so tests that
+//! use them should run with `--test-threads=1`. The crate's own tests take a
+//! lock around them instead. The one exception is the held lock stack of
+//! `interrupt::lock_order`, which is kept per thread like the mask.
+//!
+//! Nothing is dispatched by default: a pending line stays pending until the
+//! test clears it. A test that wants interrupts taken installs a hook with
+//! `on_interrupt`. Time passes through `advance` and `waitirq`, and every access
+//! to the timer counter moves it on by the poll cost set with
+//! `set_poll_cycles` (one cycle by default) before the access takes effect.
+//! Code that polls the counter until it expires, such as
+//! `timer::delay_long`, therefore finishes, after about one poll per
+//! `poll_cycles` cycles of delay. `delay_cycles` moves the time on by the
+//! cycles its loop would take.
+//!
+//! `ebreak` raises the trap on IRQ line 1, and panics where the core would
+//! halt. `semihost_write` is serviced by a simulated debugger that keeps the
+//! bytes for `take_semihost_output`.
+//!
+//! Note: this module is only available when the `mock-host` feature is
+//! enabled and the target is not RISC-V.
+
+use std::cell::Cell;
+#[cfg(feature = "semihosting")]
+use std::{cell::RefCell, vec::Vec};
+
+const TIMER_LINE: u32 = 1 << 0;
+const EBREAK_LINE: u32 = 1 << 1;
+
+#[derive(Clone, Copy)]
+struct State {
+    mask: u32,
+    pending: u32,
+    counter: u32,
+    poll_cycles: u32,
+    expiries: u32,
+    ebreaks: u32,
+    hook: Option<fn(u32)>,
+    in_handler: bool,
+    #[cfg(feature = "counters")]
+    cycles: u64,
+    #[cfg(feature = "interrupts-qregs")]
+    q: [u32; 4],
+}
+
+impl State {
+    // The core comes out of reset with every interrupt masked
+    const RESET: State = State {
+        mask: 0xffff_ffff,
+        pending: 0,
+        counter: 0,
+        poll_cycles: 1,
+        expiries: 0,
+        ebreaks: 0,
+        hook: None,
+        in_handler: false,
+        #[cfg(feature = "counters")]
+        cycles: 0,
+        #[cfg(feature = "interrupts-qregs")]
+        q: [0; 4],
+    };
+}
+
+std::thread_local! {
+    static STATE: Cell<State> = const { Cell::new(State::RESET) };
+}
+
+#[cfg(feature = "semihosting")]
+std::thread_local! {
+    static SEMIHOST_OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+fn with_state<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut State) -> R,
+{
+    STATE.with(|cell| {
+        let mut state = cell.get();
+        let r = f(&mut state);
+        cell.set(state);
+
+        r
+    })
+}
+
+/// Puts the simulated core of the current thread back in its reset state
+///
+/// Every interrupt is masked, nothing is pending, the timer is stopped, the
+/// poll cost is one cycle, no `on_interrupt` hook is installed and the cycle
+/// counter, the expiry and `ebreak` counts and the q-registers are zero. The
+/// semihosting output is discarded.
+pub fn reset() {
+    with_state(|state| *state = State::RESET);
+
+    #[cfg(feature = "semihosting")]
+    SEMIHOST_OUTPUT.with(|output| output.borrow_mut().clear());
+}
+
+/// Returns the simulated IRQ mask
+pub fn mask() -> u32 {
+    with_state(|state| state.mask)
+}
+
+/// Returns the simulated bitmask of pending IRQs
+pub fn pending() -> u32 {
+    with_state(|state| state.pending)
+}
+
+/// Makes the IRQs in `lines` pending
+///
+/// A `0b1` at any offset in `lines` selects that line. Lines that are
+/// already pending stay pending. Lines that are not masked are taken at once
+/// if an `on_interrupt` hook is installed.
+pub fn raise(lines: u32) {
+    with_state(|state| state.pending |= lines);
+    take_interrupts();
+}
+
+/// Clears the pending bits of the IRQs in `lines`
+///
+/// This stands in for the core entering the handler of those lines.
+pub fn clear(lines: u32) {
+    with_state(|state| state.pending &= !lines);
+}
+
+/// Returns the simulated timer counter, without changing it
+pub fn counter() -> u32 {
+    with_state(|state| state.counter)
+}
+
+/// Returns how many times the simulated timer has expired since the last
+/// `reset`
+pub fn expiries() -> u32 {
+    with_state(|state| state.expiries)
+}
+
+/// Returns how many `ebreak`s have been executed since the last `reset`
+pub fn ebreaks() -> u32 {
+    with_state(|state| state.ebreaks)
+}
+
+/// Returns the bytes written with `asm::semihost_write` since the last call
+/// or `reset`, and discards them
+///
+/// The simulated debugger services every call, whatever the file
+/// descriptor, so the output of all of them ends up here in order.
+///
+/// Note: this function is only available when the `semihosting` feature is
+/// enabled.
+#[cfg(feature = "semihosting")]
+pub fn take_semihost_output() -> Vec<u8> {
+    SEMIHOST_OUTPUT.with(|output| core::mem::take(&mut *output.borrow_mut()))
+}
+
+/// Sets how many cycles pass on each access to the timer counter
+///
+/// Each `asm::timer` call first moves the simulated time on by `cycles`,
+/// then reads and writes the counter. Zero makes the accesses free, so a
+/// loop polling the counter never sees it expire.
+pub fn set_poll_cycles(cycles: u32) {
+    with_state(|state| state.poll_cycles = cycles);
+}
+
+/// Moves the simulated timer on by `cycles` cycles
+///
+/// If the counter is running and reaches zero the timer IRQ (line 0) becomes
+/// pending, like the 1 to 0 transition on the core. A stopped counter stays
+/// at zero. With the `counters` feature the cycle counter moves on too.
+pub fn advance(cycles: u32) {
+    with_state(|state| {
+        #[cfg(feature = "counters")]
+        {
+            state.cycles = state.cycles.wrapping_add(u64::from(cycles));
+        }
+
+        if state.counter == 0 {
+            return;
+        }
+
+        if cycles >= state.counter {
+            state.counter = 0;
+            state.pending |= TIMER_LINE;
+            state.expiries += 1;
+        } else {
+            state.counter -= cycles;
+        }
+    });
+}
+
+/// Installs a hook that takes interrupts, or removes it with `None`
+///
+/// With a hook installed, pending lines that are not masked are taken
+/// whenever `maskirq` installs a mask or `raise` makes lines pending: their
+/// pending bits are cleared and the hook is called with them, like the core
+/// entering its interrupt handler with `q1` set. Passing
+/// `interrupt::dispatch` runs the handlers of the active table. No further
+/// interrupt is taken while the hook runs; lines that it leaves pending and
+/// unmasked are taken when it returns.
+///
+/// Installing the hook takes nothing by itself. Lines that are already
+/// pending and unmasked at that point are taken on the next `maskirq` or
+/// `raise`, like an interrupt that arrives just before the core's next
+/// instruction.
+///
+/// ```
+/// use coucal_rs::{asm, mock};
+/// use std::sync::atomic::{AtomicU32, Ordering};
+///
+/// static TAKEN: AtomicU32 = AtomicU32::new(0);
+///
+/// fn take(lines: u32) {
+///     TAKEN.fetch_or(lines, Ordering::Relaxed);
+/// }
+///
+/// mock::reset();
+/// mock::on_interrupt(Some(take));
+/// mock::raise(1 << 5);
+/// assert_eq!(TAKEN.load(Ordering::Relaxed), 0);
+///
+/// unsafe { asm::maskirq(0) };
+/// assert_eq!(TAKEN.load(Ordering::Relaxed), 1 << 5);
+/// assert_eq!(mock::pending(), 0);
+/// ```
+pub fn on_interrupt(hook: Option<fn(u32)>) {
+    with_state(|state| state.hook = hook);
+}
+
+fn take_interrupts() {
+    loop {
+        let taken = with_state(|state| {
+            let lines = state.pending & !state.mask;
+
+            match state.hook {
+                Some(hook) if !state.in_handler && lines != 0 => {
+                    state.pending &= !lines;
+                    state.in_handler = true;
+
+                    Some((hook, lines))
+                }
+                _ => None,
+            }
+        });
+
+        match taken {
+            Some((hook, lines)) => {
+                hook(lines);
+                with_state(|state| state.in_handler = false);
+            }
+            None => return,
+        }
+    }
+}
+
+/// Serializes the crate's tests that use its statics
+#[cfg(test)]
+pub(crate) fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    // A failed test poisons the lock, which must not fail the others
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn maskirq(mask: u32) -> u32 {
+    let old_mask = with_state(|state| core::mem::replace(&mut state.mask, mask));
+    take_interrupts();
+
+    old_mask
+}
+
+/// Returns immediately if an IRQ is pending. Otherwise a running timer is
+/// advanced to its expiry, since nothing else could make an IRQ pending.
+pub(crate) fn waitirq() -> u32 {
+    let counter = counter();
+
+    if pending() == 0 && counter != 0 {
+        advance(counter);
+    }
+
+    let pending = pending();
+
+    assert!(pending != 0, "waitirq with nothing pending never returns");
+
+    pending
+}
+
+/// Counts the `ebreak` and raises the trap on line 1. If that line is masked,
+/// or an interrupt is already being taken, the core would halt instead, which
+/// the mock turns into a panic.
+pub(crate) fn ebreak() {
+    let halts = with_state(|state| {
+        state.ebreaks += 1;
+
+        state.in_handler || state.mask & EBREAK_LINE != 0
+    });
+
+    assert!(!halts, "ebreak with IRQ line 1 masked halts the core");
+
+    raise(EBREAK_LINE);
+}
+
+#[cfg(feature = "semihosting")]
+pub(crate) fn semihost_write(ptr: *const u8, len: usize) -> usize {
+    // SAFETY: the caller of `asm::semihost_write` guarantees `ptr` is valid
+    // for reads of `len` bytes
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+
+    SEMIHOST_OUTPUT.with(|output| output.borrow_mut().extend_from_slice(bytes));
+
+    0
+}
+
+#[cfg(feature = "timer")]
+pub(crate) fn timer(cycles: u32) -> u32 {
+    advance(with_state(|state| state.poll_cycles));
+
+    with_state(|state| core::mem::replace(&mut state.counter, cycles))
+}
+
+#[cfg(feature = "counters")]
+pub(crate) fn cycles() -> u64 {
+    with_state(|state| state.cycles)
+}
+
+#[cfg(feature = "interrupts-qregs")]
+pub(crate) fn getq(n: u32) -> u32 {
+    with_state(|state| state.q[n as usize])
+}
+
+#[cfg(feature = "interrupts-qregs")]
+pub(crate) fn setq(n: u32, val: u32) {
+    with_state(|state| state.q[n as usize] = val);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn reset_masks_everything() {
+        reset();
+
+        assert_eq!(mask(), 0xffff_ffff);
+        assert_eq!(pending(), 0);
+        assert_eq!(counter(), 0);
+    }
+
+    #[test]
+    fn maskirq_returns_the_old_mask() {
+        reset();
+
+        assert_eq!(unsafe { asm::maskirq(0b1010) }, 0xffff_ffff);
+        assert_eq!(unsafe { asm::maskirq(0) }, 0b1010);
+        assert_eq!(mask(), 0);
+    }
+
+    #[test]
+    fn waitirq_returns_and_keeps_pending_lines() {
+        reset();
+        raise(0b1000);
+
+        assert_eq!(unsafe { asm::waitirq() }, 0b1000);
+        assert_eq!(pending(), 0b1000);
+
+        clear(0b1000);
+        assert_eq!(pending(), 0);
+    }
+
+    #[test]
+    fn waitirq_wakes_on_masked_lines() {
+        reset();
+        raise(0b100);
+
+        assert_eq!(mask(), 0xffff_ffff);
+        assert_eq!(unsafe { asm::waitirq() }, 0b100);
+    }
+
+    #[test]
+    fn waitirq_runs_the_timer_to_expiry() {
+        reset();
+        unsafe {
+            asm::timer(50);
+        }
+
+        assert_eq!(unsafe { asm::waitirq() }, TIMER_LINE);
+        assert_eq!(counter(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "never returns")]
+    fn waitirq_with_nothing_pending_panics() {
+        reset();
+
+        unsafe {
+            asm::waitirq();
+        }
+    }
+
+    #[test]
+    fn timer_is_read_by_writing() {
+        reset();
+        set_poll_cycles(0);
+
+        assert_eq!(unsafe { asm::timer(100) }, 0);
+        advance(30);
+        assert_eq!(unsafe { asm::timer(0) }, 70);
+        // Reading with zero stopped the counter
+        assert_eq!(counter(), 0);
+        advance(100);
+        assert_eq!(pending(), 0);
+    }
+
+    #[test]
+    fn timer_access_costs_poll_cycles() {
+        reset();
+
+        unsafe {
+            asm::timer(10);
+        }
+        assert_eq!(unsafe { asm::timer(0) }, 9);
+
+        set_poll_cycles(4);
+        unsafe {
+            asm::timer(10);
+        }
+        assert_eq!(unsafe { asm::timer(0) }, 6);
+    }
+
+    #[test]
+    fn timer_fires_on_reaching_zero() {
+        reset();
+        set_poll_cycles(0);
+
+        unsafe {
+            asm::timer(2);
+        }
+        advance(1);
+        assert_eq!(pending(), 0);
+        advance(1);
+        assert_eq!(pending(), TIMER_LINE);
+        assert_eq!(expiries(), 1);
+
+        // A stopped timer doesn't fire again
+        clear(TIMER_LINE);
+        advance(10);
+        assert_eq!(pending(), 0);
+        assert_eq!(expiries(), 1);
+    }
+
+    #[cfg(feature = "interrupts-qregs")]
+    #[test]
+    fn q_registers_round_trip() {
+        reset();
+
+        unsafe {
+            asm::setq::<0>(0x10);
+            asm::setq::<1>(0x11);
+            asm::setq2(0x12);
+            asm::setq3(0x13);
+
+            assert_eq!(asm::getq0(), 0x10);
+            assert_eq!(asm::getq1(), 0x11);
+            assert_eq!(asm::getq::<2>(), 0x12);
+            assert_eq!(asm::getq3(), 0x13);
+        }
+
+        reset();
+        assert_eq!(unsafe { asm::getq2() }, 0);
+    }
+
+    #[test]
+    fn the_hook_takes_unmasked_lines() {
+        std::thread_local! {
+            static TAKEN: Cell<[u32; 3]> = const { Cell::new([0; 3]) };
+        }
+
+        fn take(lines: u32) {
+            TAKEN.with(|taken| {
+                let mut log = taken.get();
+                log.rotate_left(1);
+                log[2] = lines;
+                taken.set(log);
+            });
+
+            // Not taken before this returns
+            if lines == 1 << 2 {
+                raise(1 << 7);
+                assert_eq!(pending(), (1 << 7) | (1 << 3));
+            }
+        }
+
+        reset();
+        raise((1 << 3) | (1 << 2));
+        on_interrupt(Some(take));
+        assert_eq!(pending(), (1 << 3) | (1 << 2));
+
+        unsafe {
+            asm::maskirq(!((1 << 7) | (1 << 2)));
+        }
+        assert_eq!(TAKEN.with(Cell::get), [0, 1 << 2, 1 << 7]);
+        assert_eq!(pending(), 1 << 3);
+
+        on_interrupt(None);
+        unsafe {
+            asm::maskirq(0);
+        }
+        assert_eq!(pending(), 1 << 3);
+    }
+
+    #[test]
+    fn ebreak_raises_the_trap_line() {
+        reset();
+
+        unsafe {
+            asm::maskirq(!EBREAK_LINE);
+            asm::ebreak();
+        }
+
+        assert_eq!(pending(), EBREAK_LINE);
+        assert_eq!(ebreaks(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ebreak with IRQ line 1 masked halts the core")]
+    fn ebreak_with_the_trap_line_masked_panics() {
+        reset();
+
+        unsafe {
+            asm::ebreak();
+        }
+    }
+
+    #[test]
+    fn ebreak_in_the_hook_panics() {
+        fn take(_: u32) {
+            unsafe {
+                asm::ebreak();
+            }
+        }
+
+        reset();
+        on_interrupt(Some(take));
+
+        let halted = std::panic::catch_unwind(|| unsafe {
+            asm::maskirq(0);
+            raise(1 << 4);
+        });
+
+        assert!(halted.is_err());
+        assert_eq!(ebreaks(), 1);
+    }
+
+    #[cfg(feature = "semihosting")]
+    #[test]
+    fn semihost_write_keeps_the_output() {
+        reset();
+
+        unsafe {
+            assert_eq!(asm::semihost_write(1, b"ab".as_ptr(), 2), 0);
+            assert_eq!(asm::semihost_write(2, b"cde".as_ptr(), 3), 0);
+        }
+
+        assert_eq!(take_semihost_output(), b"abcde");
+        assert!(take_semihost_output().is_empty());
+    }
+}
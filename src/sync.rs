@@ -0,0 +1,359 @@
+//! Synchronization primitives built on `interrupt::free`
+//!
+//! `Once` and `Lazy` hold a value that is initialized the first time it is
+//! needed, from whichever context gets there first. The initialization runs
+//! inside an `interrupt::free` critical section, so an interrupt handler can
+//! never see it half done and it runs exactly once, even if main code and a
+//! handler race for it.
+//!
+//! `IrqMutex` shares a value between main code and interrupt handlers,
+//! entering the critical section itself on each access. `IrqShared` does the
+//! same for values that only need `&T`, such as a peripheral's register
+//! block.
+
+use crate::interrupt::free;
+use core::cell::{Cell, UnsafeCell};
+use core::ops::Deref;
+
+/// A value that is initialized once
+///
+/// ```ignore
+/// static UART: Once<Uart> = Once::new();
+///
+/// let uart = UART.call_once(|| Uart::new(UART_BASE));
+/// ```
+pub struct Once<T> {
+    value: UnsafeCell<Option<T>>,
+    running: Cell<bool>,
+}
+
+// The value is only written inside a critical section, and never again once
+// it has been set.
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    /// Creates an uninitialized `Once`
+    pub const fn new() -> Self {
+        Once {
+            value: UnsafeCell::new(None),
+            running: Cell::new(false),
+        }
+    }
+
+    /// Initializes the value with `f` if that hasn't happened yet, and
+    /// returns it
+    ///
+    /// Only the first call runs `f`. Later calls return the value the first
+    /// call stored. `f` runs with interrupts masked, so it should be short.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` calls `call_once` on the same `Once`.
+    pub fn call_once<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        free(|_| {
+            if unsafe { (*self.value.get()).is_none() } {
+                assert!(
+                    !self.running.replace(true),
+                    "Once::call_once called recursively"
+                );
+
+                let value = f();
+
+                unsafe {
+                    *self.value.get() = Some(value);
+                }
+                self.running.set(false);
+            }
+        });
+
+        match self.get() {
+            Some(value) => value,
+            None => unreachable!(),
+        }
+    }
+
+    /// Returns the value if it has been initialized
+    pub fn get(&self) -> Option<&T> {
+        free(|_| unsafe { (*self.value.get()).as_ref() })
+    }
+
+    /// Returns `true` if the value has been initialized
+    pub fn is_completed(&self) -> bool {
+        self.get().is_some()
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Once::new()
+    }
+}
+
+/// A value that is initialized on first access
+///
+/// ```ignore
+/// static TABLE: Lazy<[u16; 256]> = Lazy::new(build_crc_table);
+///
+/// let crc = TABLE[byte as usize];
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+// `init` is only taken inside the critical section of `Once::call_once`.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F> Lazy<T, F> {
+    /// Creates a `Lazy` that is initialized by `init`
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F> Lazy<T, F>
+where
+    F: FnOnce() -> T,
+{
+    /// Initializes the value if that hasn't happened yet, and returns it
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| match this.init.take() {
+            Some(init) => init(),
+            None => unreachable!(),
+        })
+    }
+}
+
+impl<T, F> Deref for Lazy<T, F>
+where
+    F: FnOnce() -> T,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+/// A value shared with interrupt handlers, locked by masking interrupts
+///
+/// This is `bare_metal::Mutex` with the critical section taken care of:
+/// `lock` runs its closure inside `interrupt::free` and hands it the value.
+///
+/// ```ignore
+/// static COUNTER: IrqMutex<u32> = IrqMutex::new(0);
+///
+/// fn on_timer(_: &HandlerContext) {
+///     COUNTER.lock(|count| *count += 1);
+/// }
+/// ```
+pub struct IrqMutex<T> {
+    value: UnsafeCell<T>,
+    locked: Cell<bool>,
+}
+
+// The value is only accessed inside a critical section, and `locked` stops
+// a closure from reaching it a second time.
+unsafe impl<T: Send> Sync for IrqMutex<T> {}
+
+impl<T> IrqMutex<T> {
+    /// Creates a mutex holding `value`
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            value: UnsafeCell::new(value),
+            locked: Cell::new(false),
+        }
+    }
+
+    /// Runs `f` with exclusive access to the value, with interrupts masked
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` locks the same mutex again.
+    pub fn lock<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        free(|_| {
+            assert!(!self.locked.replace(true), "IrqMutex locked recursively");
+
+            let r = f(unsafe { &mut *self.value.get() });
+
+            self.locked.set(false);
+
+            r
+        })
+    }
+
+    /// Returns a mutable reference to the value
+    ///
+    /// No critical section is needed, since `&mut self` proves there is no
+    /// other access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Consumes the mutex and returns the value
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for IrqMutex<T> {
+    fn default() -> Self {
+        IrqMutex::new(T::default())
+    }
+}
+
+/// A value shared with interrupt handlers through shared references
+///
+/// `with` is the only way to reach the value, and it runs its closure inside
+/// `interrupt::free`. That is what makes the `Sync` impl sound: PicoRV32 has
+/// a single hart, so with every interrupt masked nothing else can run while
+/// the closure holds the reference. Main code and handlers therefore never
+/// use the value at the same time, and `T` only has to be `Send`, as it
+/// would for a move into the handler.
+///
+/// Unlike `IrqMutex` it hands out `&T`, so calls to `with` can nest.
+///
+/// ```ignore
+/// static UART: IrqShared<Uart> = IrqShared::new(Uart::at(UART_BASE));
+///
+/// fn on_uart(_: &HandlerContext) {
+///     UART.with(|uart| uart.clear_rx_irq());
+/// }
+/// ```
+pub struct IrqShared<T> {
+    value: T,
+}
+
+// The value is only reachable through `with`, inside a critical section.
+unsafe impl<T: Send> Sync for IrqShared<T> {}
+
+impl<T> IrqShared<T> {
+    /// Wraps `value`
+    pub const fn new(value: T) -> Self {
+        IrqShared { value }
+    }
+
+    /// Runs `f` with a reference to the value, with interrupts masked
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        free(|_| f(&self.value))
+    }
+
+    /// Consumes the wrapper and returns the value
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn once_runs_the_initializer_once() {
+        mock::reset();
+
+        let once = Once::new();
+        let calls = Cell::new(0);
+
+        assert_eq!(once.get(), None);
+        assert!(!once.is_completed());
+
+        for _ in 0..3 {
+            let value = once.call_once(|| {
+                calls.set(calls.get() + 1);
+
+                42
+            });
+
+            assert_eq!(*value, 42);
+        }
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(once.get(), Some(&42));
+        assert!(once.is_completed());
+    }
+
+    #[test]
+    fn lazy_runs_the_initializer_once_on_first_use() {
+        mock::reset();
+
+        let calls = Cell::new(0);
+        let lazy = Lazy::new(|| {
+            calls.set(calls.get() + 1);
+
+            7
+        });
+
+        assert_eq!(calls.get(), 0);
+        assert_eq!(*lazy, 7);
+        assert_eq!(*Lazy::force(&lazy), 7);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn irq_mutex_locks_with_interrupts_masked() {
+        mock::reset();
+
+        unsafe {
+            crate::asm::maskirq(0b10);
+        }
+
+        let mutex = IrqMutex::new(1);
+
+        let masked = mutex.lock(|value| {
+            *value += 1;
+
+            mock::mask()
+        });
+
+        assert_eq!(masked, 0xffff_ffff);
+        assert_eq!(mock::mask(), 0b10);
+        assert_eq!(mutex.lock(|value| *value), 2);
+        assert_eq!(mutex.into_inner(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "IrqMutex locked recursively")]
+    fn irq_mutex_panics_when_locked_recursively() {
+        mock::reset();
+
+        let mutex = IrqMutex::new(0);
+
+        mutex.lock(|_| mutex.lock(|_| ()));
+    }
+
+    #[test]
+    fn irq_shared_hands_out_the_value_with_interrupts_masked() {
+        mock::reset();
+
+        unsafe {
+            crate::asm::maskirq(0b10);
+        }
+
+        let shared = IrqShared::new(Cell::new(3));
+
+        let masked = shared.with(|value| {
+            // Calls can nest, unlike `IrqMutex::lock`.
+            shared.with(|inner| inner.set(value.get() + 1));
+
+            mock::mask()
+        });
+
+        assert_eq!(masked, 0xffff_ffff);
+        assert_eq!(mock::mask(), 0b10);
+        assert_eq!(shared.into_inner().get(), 4);
+    }
+}
@@ -0,0 +1,249 @@
+//! Monotonic time from the cycle counter
+//!
+//! `Instant` is a reading of the 64 bit cycle counter and `Duration` a number
+//! of cycles, so measuring time needs neither the timer nor its interrupt:
+//!
+//! ```ignore
+//! const CLOCK_HZ: u32 = 48_000_000;
+//!
+//! let start = unsafe { Instant::now() };
+//! let reply = uart.poll_reply();
+//! if unsafe { start.elapsed() } > Duration::from_micros(500, CLOCK_HZ) {
+//!     log::warn!("slow reply");
+//! }
+//! ```
+//!
+//! The counter only wraps after 2^64 cycles, centuries at any clock PicoRV32
+//! runs at, so instants compare in the order they were taken. Conversions to
+//! and from units of time take the core clock frequency in Hz, which must
+//! not be zero, like the functions of the `timer` module.
+//!
+//! Note: this module is only available when the `counters` feature is
+//! enabled.
+
+use crate::asm::cycles64;
+use core::ops::{Add, Sub};
+
+/// Returns the number of cycles of a `hz` clock in `amount` units of
+/// `1 / per_second` seconds, rounded up and saturating at `u64::MAX`
+const fn cycles_for(hz: u32, amount: u64, per_second: u64) -> u64 {
+    let hz = hz as u64;
+
+    // Split `amount` so that no product can overflow unless the result does.
+    let whole = (amount / per_second).saturating_mul(hz);
+    let part = ((amount % per_second) * hz).div_ceil(per_second);
+
+    whole.saturating_add(part)
+}
+
+/// Returns the number of whole units of `1 / per_second` seconds in `cycles`
+/// cycles of a `hz` clock, saturating at `u64::MAX`
+const fn amount_for(hz: u32, cycles: u64, per_second: u64) -> u64 {
+    let hz = hz as u64;
+
+    let whole = (cycles / hz).saturating_mul(per_second);
+    let part = (cycles % hz) * per_second / hz;
+
+    whole.saturating_add(part)
+}
+
+/// A span of time, counted in core clock cycles
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration {
+    cycles: u64,
+}
+
+impl Duration {
+    /// A duration of zero cycles
+    pub const ZERO: Duration = Duration { cycles: 0 };
+
+    /// Creates a duration of `cycles` cycles
+    pub const fn from_cycles(cycles: u64) -> Self {
+        Duration { cycles }
+    }
+
+    /// Creates a duration of `us` microseconds of a `hz` clock
+    ///
+    /// The number of cycles is rounded up, so the duration is never shorter
+    /// than asked for.
+    pub const fn from_micros(us: u64, hz: u32) -> Self {
+        Duration::from_cycles(cycles_for(hz, us, 1_000_000))
+    }
+
+    /// Creates a duration of `ms` milliseconds of a `hz` clock
+    ///
+    /// The number of cycles is rounded up, like `from_micros`.
+    pub const fn from_millis(ms: u64, hz: u32) -> Self {
+        Duration::from_cycles(cycles_for(hz, ms, 1_000))
+    }
+
+    /// Returns the number of cycles in the duration
+    pub const fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Returns the number of whole microseconds in the duration at `hz`
+    pub const fn as_micros(&self, hz: u32) -> u64 {
+        amount_for(hz, self.cycles, 1_000_000)
+    }
+
+    /// Returns the number of whole milliseconds in the duration at `hz`
+    pub const fn as_millis(&self, hz: u32) -> u64 {
+        amount_for(hz, self.cycles, 1_000)
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    /// Adds two durations, saturating at `u64::MAX` cycles
+    fn add(self, rhs: Duration) -> Duration {
+        Duration::from_cycles(self.cycles.saturating_add(rhs.cycles))
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    /// Subtracts two durations, saturating at zero
+    fn sub(self, rhs: Duration) -> Duration {
+        Duration::from_cycles(self.cycles.saturating_sub(rhs.cycles))
+    }
+}
+
+/// A point in time, as a reading of the cycle counter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    cycles: u64,
+}
+
+impl Instant {
+    /// Returns the current instant
+    ///
+    /// # Safety
+    ///
+    /// - The requirements of `asm::cycles64` apply
+    #[inline]
+    pub unsafe fn now() -> Self {
+        Instant { cycles: cycles64() }
+    }
+
+    /// Returns the cycle count of the instant
+    pub const fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Returns the time from `earlier` to this instant, or zero if `earlier`
+    /// is later
+    pub const fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_cycles(self.cycles.saturating_sub(earlier.cycles))
+    }
+
+    /// Returns the time since this instant
+    ///
+    /// # Safety
+    ///
+    /// - The requirements of `asm::cycles64` apply
+    #[inline]
+    pub unsafe fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Returns the instant `rhs` after this one, saturating at the largest
+    /// cycle count
+    fn add(self, rhs: Duration) -> Instant {
+        Instant {
+            cycles: self.cycles.saturating_add(rhs.cycles),
+        }
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    /// Same as `duration_since`
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[cfg(all(test, feature = "mock-host"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn elapsed_counts_the_cycles_since_now() {
+        mock::reset();
+
+        let start = unsafe { Instant::now() };
+        mock::advance(1_000);
+        let end = unsafe { Instant::now() };
+
+        assert_eq!(end.duration_since(start), Duration::from_cycles(1_000));
+        assert_eq!(end - start, Duration::from_cycles(1_000));
+        assert_eq!(start.duration_since(end), Duration::ZERO);
+        assert_eq!(unsafe { start.elapsed() }, Duration::from_cycles(1_000));
+    }
+
+    #[test]
+    fn instants_keep_their_order_across_the_low_word_wrapping() {
+        mock::reset();
+        mock::advance(u32::MAX - 5);
+
+        let start = unsafe { Instant::now() };
+        mock::advance(10);
+        let end = unsafe { Instant::now() };
+
+        assert_eq!(end.cycles(), u64::from(u32::MAX) + 5);
+        assert!(end > start);
+        assert_eq!(end.duration_since(start), Duration::from_cycles(10));
+    }
+
+    #[test]
+    fn conversions_round_towards_the_safe_side() {
+        assert_eq!(Duration::from_micros(250, 48_000_000).cycles(), 12_000);
+        assert_eq!(Duration::from_millis(1, 12_000_000).cycles(), 12_000);
+
+        // 1.5 cycles are rounded up, and back down again
+        assert_eq!(Duration::from_micros(1, 1_500_000).cycles(), 2);
+        assert_eq!(Duration::from_cycles(2).as_micros(1_500_000), 1);
+        assert_eq!(Duration::from_cycles(47_999).as_millis(48_000_000), 0);
+    }
+
+    #[test]
+    fn conversions_saturate_instead_of_overflowing() {
+        assert_eq!(
+            Duration::from_millis(u64::MAX, 100_000_000).cycles(),
+            u64::MAX
+        );
+        assert_eq!(
+            Duration::from_micros(u64::MAX, 1).cycles(),
+            u64::MAX / 1_000_000 + 1
+        );
+        assert_eq!(Duration::from_cycles(u64::MAX).as_micros(1), u64::MAX);
+        assert_eq!(
+            Duration::from_cycles(u64::MAX).as_millis(48_000_000),
+            u64::MAX / 48_000
+        );
+    }
+
+    #[test]
+    fn arithmetic_saturates() {
+        let max = Duration::from_cycles(u64::MAX);
+        let one = Duration::from_cycles(1);
+
+        assert_eq!(max + one, max);
+        assert_eq!(one - max, Duration::ZERO);
+
+        let start = Instant {
+            cycles: u64::MAX - 1,
+        };
+
+        assert_eq!((start + max).cycles(), u64::MAX);
+    }
+}
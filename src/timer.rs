@@ -0,0 +1,726 @@
+//! Timer
+//!
+//! PicoRV32 cores built without `ENABLE_IRQ_TIMER` have no timer
+//! instruction. The `timer` feature (enabled by default) controls whether
+//! this crate assumes the timer is present. When the feature is disabled
+//! `asm::timer` and every helper in this module that relies on it are not
+//! compiled, so firmware cannot silently depend on a missing timer. Use
+//! `is_available` to branch on it in downstream code.
+
+#[cfg(feature = "timer")]
+use crate::asm::*;
+#[cfg(feature = "timer")]
+use crate::interrupt;
+
+/// Returns `true` if the `timer` feature is enabled
+///
+/// This is fixed at compile time.
+pub const fn is_available() -> bool {
+    cfg!(feature = "timer")
+}
+
+/// Blocks for at least `total_cycles` clock cycles
+///
+/// The timer counter is 32 bits wide, so a delay longer than `u32::MAX`
+/// cycles is split into several timer arms which are waited on one after the
+/// other. The last arm covers whatever is left over.
+///
+/// All interrupts are masked for the duration of the delay so that the
+/// intermediate expiries are not serviced. The mask in place before the call
+/// is restored before returning. The timer interrupt is left pending by the
+/// final expiry, as it is after any other countdown.
+///
+/// Expiry is detected by polling the counter (see `wait_expired`). Each poll
+/// stops the counter for a few cycles, so the actual delay is slightly longer
+/// than requested.
+///
+/// Any countdown that was running when this function is called is discarded.
+///
+/// Note: this function is only available when the `timer` feature is enabled.
+#[cfg(feature = "timer")]
+pub fn delay_long(total_cycles: u64) {
+    let old_mask = unsafe { maskirq(0xffff_ffff) };
+
+    let mut remaining = total_cycles;
+
+    while remaining > 0 {
+        let chunk = if remaining > u64::from(u32::MAX) {
+            u32::MAX
+        } else {
+            remaining as u32
+        };
+
+        unsafe {
+            timer(chunk);
+        }
+        wait_expired();
+
+        remaining -= u64::from(chunk);
+    }
+
+    unsafe {
+        maskirq(old_mask);
+    }
+}
+
+/// Pushes the timer deadline out by `additional` cycles
+///
+/// The current count is read and the timer re-armed with the count plus
+/// `additional`, saturating at `u32::MAX`. The new count is returned. This
+/// is useful for sliding timeouts, where each bit of activity pushes the
+/// deadline further out.
+///
+/// Reading the count means writing zero to the counter, so the counter is
+/// stopped for the few cycles between the read and the re-arm, and the
+/// deadline moves out slightly more than requested. Both happen inside a
+/// critical section so an interrupt can't stretch that window.
+///
+/// If the timer has already expired (or was never armed) it is armed for
+/// `additional` cycles.
+///
+/// Note: this function is only available when the `timer` feature is enabled.
+#[cfg(feature = "timer")]
+pub fn extend(additional: u32) -> u32 {
+    interrupt::free(|_| unsafe {
+        let new = timer(0).saturating_add(additional);
+        timer(new);
+
+        new
+    })
+}
+
+/// Returns the number of cycles of a `hz` clock in `us` microseconds
+///
+/// The result is rounded up, so a timer armed with it never fires early, and
+/// saturates at `u32::MAX` for durations the counter can't represent.
+pub const fn cycles_for_us(hz: u32, us: u32) -> u32 {
+    let cycles = (us as u64 * hz as u64).div_ceil(1_000_000);
+
+    if cycles > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        cycles as u32
+    }
+}
+
+/// Returns the number of cycles of a `hz` clock in `ms` milliseconds
+///
+/// Like `cycles_for_us` the result is rounded up and saturates at
+/// `u32::MAX`.
+pub const fn cycles_for_ms(hz: u32, ms: u32) -> u32 {
+    let cycles = (ms as u64 * hz as u64).div_ceil(1_000);
+
+    if cycles > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        cycles as u32
+    }
+}
+
+/// Returns the number of whole microseconds in `cycles` cycles of a `hz`
+/// clock
+///
+/// The result is rounded down. `hz` must not be zero.
+pub const fn us_for_cycles(hz: u32, cycles: u32) -> u32 {
+    let us = cycles as u64 * 1_000_000 / hz as u64;
+
+    if us > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        us as u32
+    }
+}
+
+/// The core clock, for working with the timer in units of time
+///
+/// ```ignore
+/// const CLOCK: Clock = Clock::new(48_000_000);
+///
+/// CLOCK.arm_us(250);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clock {
+    /// Core clock frequency in Hz, which must not be zero
+    pub hz: u32,
+}
+
+impl Clock {
+    /// Creates a clock running at `hz` Hz
+    pub const fn new(hz: u32) -> Self {
+        Clock { hz }
+    }
+
+    /// Returns the longest duration the timer can count, in microseconds
+    ///
+    /// This is `u32::MAX` cycles, for example about 89 seconds at 48 MHz.
+    pub const fn max_us(&self) -> u32 {
+        us_for_cycles(self.hz, u32::MAX)
+    }
+
+    /// Returns the longest duration the timer can count, in milliseconds
+    ///
+    /// This is `max_us` rounded down to whole milliseconds.
+    pub const fn max_ms(&self) -> u32 {
+        self.max_us() / 1_000
+    }
+
+    /// Arms the timer to fire `us` microseconds from now
+    ///
+    /// Durations longer than `max_us` saturate to the longest countdown the
+    /// timer can do. Returns the previous counter value in cycles, like
+    /// `asm::timer`.
+    ///
+    /// Note: this function is only available when the `timer` feature is
+    /// enabled.
+    #[cfg(feature = "timer")]
+    pub fn arm_us(&self, us: u32) -> u32 {
+        unsafe { timer(cycles_for_us(self.hz, us)) }
+    }
+
+    /// Arms the timer to fire `ms` milliseconds from now
+    ///
+    /// Durations longer than `max_ms` saturate to the longest countdown the
+    /// timer can do. Returns the previous counter value in cycles, like
+    /// `asm::timer`.
+    ///
+    /// Note: this function is only available when the `timer` feature is
+    /// enabled.
+    #[cfg(feature = "timer")]
+    pub fn arm_ms(&self, ms: u32) -> u32 {
+        unsafe { timer(cycles_for_ms(self.hz, ms)) }
+    }
+
+    /// Returns the time left until the timer fires, in whole microseconds
+    ///
+    /// The count is read by writing to the counter, see `extend` for the
+    /// small loss of accuracy this causes.
+    ///
+    /// Note: this function is only available when the `timer` feature is
+    /// enabled.
+    #[cfg(feature = "timer")]
+    pub fn remaining_us(&self) -> u32 {
+        us_for_cycles(self.hz, remaining())
+    }
+}
+
+/// A timer that fires once
+///
+/// Note: this type is only available when the `timer` feature is enabled.
+#[cfg(feature = "timer")]
+#[derive(Clone, Copy, Debug)]
+pub struct OneShot;
+
+#[cfg(feature = "timer")]
+impl OneShot {
+    /// Arms the timer to fire once, `cycles` cycles from now
+    ///
+    /// The timer interrupt (line 0) goes pending when the counter reaches
+    /// zero, and the timer then stays stopped. Arming with zero stops the
+    /// timer. Returns the previous counter value, like `asm::timer`.
+    pub fn arm(cycles: u32) -> u32 {
+        unsafe { timer(cycles) }
+    }
+}
+
+/// A timer that fires every `period` cycles
+///
+/// The counter does not reload itself, so the timer interrupt handler has to
+/// call `reload` each time it fires. The next period is counted from the
+/// reload, not from the expiry, so each period is stretched by the time it
+/// takes to enter the handler and reach the reload. Use
+/// `reload_compensated` to take that overrun out of the next period.
+///
+/// ```ignore
+/// static TICK: Periodic = Periodic::new(48_000);
+///
+/// fn timer_handler() {
+///     TICK.reload();
+///     // ...
+/// }
+/// ```
+///
+/// Note: this type is only available when the `timer` feature is enabled.
+#[cfg(feature = "timer")]
+#[derive(Clone, Copy, Debug)]
+pub struct Periodic {
+    period: u32,
+}
+
+#[cfg(feature = "timer")]
+impl Periodic {
+    /// Creates a timer with a period of `period` cycles, without arming it
+    ///
+    /// `period` must not be zero.
+    pub const fn new(period: u32) -> Self {
+        Periodic { period }
+    }
+
+    /// Returns the period in cycles
+    pub const fn period(&self) -> u32 {
+        self.period
+    }
+
+    /// Arms the timer for the first period
+    ///
+    /// Returns the previous counter value, like `asm::timer`.
+    pub fn start(&self) -> u32 {
+        unsafe { timer(self.period) }
+    }
+
+    /// Arms the timer for the next period
+    ///
+    /// Call this from the timer interrupt handler.
+    pub fn reload(&self) {
+        unsafe {
+            timer(self.period);
+        }
+    }
+
+    /// Arms the timer for the next period, shortened by `overrun` cycles
+    ///
+    /// `overrun` is the number of cycles since the timer expired, for example
+    /// the handler entry latency measured with the cycle counter. If the
+    /// overrun is a whole period or more the timer is armed for a single
+    /// cycle, so the next tick fires straight away rather than being lost.
+    pub fn reload_compensated(&self, overrun: u32) {
+        let cycles = self.period.saturating_sub(overrun).max(1);
+
+        unsafe {
+            timer(cycles);
+        }
+    }
+}
+
+/// Returns the number of cycles left before the timer fires, or zero if it
+/// is stopped
+///
+/// The counter can only be read by writing a new value to it, so this writes
+/// zero, which returns the count, and then re-arms the timer with the value
+/// read back. Both writes happen with interrupts masked. The counter does not
+/// run between the two instructions, so each call delays the timer by the
+/// few cycles the second write takes to issue. That skew adds up over many
+/// calls: use this to see how far a countdown has got, not as a precise
+/// clock source.
+///
+/// Note: this function is only available when the `timer` feature is
+/// enabled.
+#[cfg(feature = "timer")]
+pub fn remaining() -> u32 {
+    interrupt::free(|_| unsafe {
+        let left = timer(0);
+
+        if left != 0 {
+            timer(left);
+        }
+
+        left
+    })
+}
+
+/// Arms the timer to fire in `cycles` cycles and unmasks the timer interrupt
+///
+/// Both steps happen with every interrupt masked, so the timer can't fire
+/// between them. This changes the global IRQ mask: line 0 is unmasked and
+/// the other lines keep their state.
+///
+/// Note: this function is only available when the `timer` feature is
+/// enabled.
+///
+/// # Safety
+///
+/// - Do not call this function inside an `interrupt::free` critical section
+#[cfg(feature = "timer")]
+pub unsafe fn arm_and_enable(cycles: u32) {
+    let old_mask = maskirq(0xffff_ffff);
+    timer(cycles);
+    maskirq(old_mask & !(1 << interrupt::TIMER_IRQ));
+}
+
+/// Stops the timer and masks the timer interrupt
+///
+/// This changes the global IRQ mask: line 0 is masked and the other lines
+/// keep their state. A timer interrupt that was already pending stays
+/// pending.
+///
+/// Note: this function is only available when the `timer` feature is
+/// enabled.
+#[cfg(feature = "timer")]
+pub fn disable() {
+    unsafe {
+        let old_mask = maskirq(0xffff_ffff);
+        timer(0);
+        maskirq(old_mask | (1 << interrupt::TIMER_IRQ));
+    }
+}
+
+/// Error returned by `with_timeout` when the timer expires first
+///
+/// Note: this type is only available when the `timer` feature is enabled.
+#[cfg(feature = "timer")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+/// Polls `f` until it returns `Some`, giving up after `cycles` cycles
+///
+/// ```ignore
+/// // Give the sensor 5 ms to produce a sample
+/// let sample = timer::with_timeout(cycles_for_ms(CLOCK_HZ, 5), || sensor.try_read())?;
+/// ```
+///
+/// The timer is armed for `cycles` and `f` is called in a loop, with the
+/// counter polled between calls. `f` gets one more call after the expiry is
+/// seen, so a result that arrives right at the deadline is not lost. On
+/// success the timer is stopped; on `Err(Timeout)` it has run out and the
+/// timer interrupt (line 0) is left pending, as after any other countdown.
+///
+/// This takes the timer over. A countdown that was running is discarded and
+/// not restored afterwards.
+///
+/// Note: this function is only available when the `timer` feature is
+/// enabled.
+#[cfg(feature = "timer")]
+pub fn with_timeout<F, R>(cycles: u32, mut f: F) -> Result<R, Timeout>
+where
+    F: FnMut() -> Option<R>,
+{
+    OneShot::arm(cycles);
+
+    loop {
+        let expired = remaining() == 0;
+
+        if let Some(r) = f() {
+            unsafe {
+                timer(0);
+            }
+
+            return Ok(r);
+        }
+
+        if expired {
+            return Err(Timeout);
+        }
+    }
+}
+
+/// Spins until the timer counter has counted down to zero
+///
+/// The counter can only be read by writing a new value to it. Each poll
+/// writes zero, which returns the count, and then immediately re-arms the
+/// timer with the value read back. The counter does not run between the two
+/// instructions.
+#[cfg(feature = "timer")]
+fn wait_expired() {
+    loop {
+        let left = unsafe { timer(0) };
+
+        if left == 0 {
+            break;
+        }
+
+        unsafe {
+            timer(left);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock-host", feature = "timer"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn delay_long_rearms_for_each_32_bit_chunk() {
+        mock::reset();
+        mock::set_poll_cycles(1 << 24);
+        unsafe {
+            maskirq(0b101);
+        }
+
+        delay_long(2 * u64::from(u32::MAX) + 10);
+
+        assert_eq!(mock::expiries(), 3);
+        assert_eq!(mock::counter(), 0);
+        assert_eq!(mock::pending(), 1 << interrupt::TIMER_IRQ);
+        assert_eq!(mock::mask(), 0b101);
+    }
+
+    #[test]
+    fn delay_long_of_zero_does_not_arm() {
+        mock::reset();
+
+        delay_long(0);
+
+        assert_eq!(mock::expiries(), 0);
+        assert_eq!(mock::pending(), 0);
+    }
+
+    #[test]
+    fn arm_and_enable_then_disable() {
+        mock::reset();
+        unsafe {
+            maskirq(0b101);
+
+            arm_and_enable(1000);
+        }
+
+        assert_eq!(mock::counter(), 1000);
+        assert_eq!(mock::mask(), 0b100);
+
+        disable();
+
+        assert_eq!(mock::counter(), 0);
+        assert_eq!(mock::mask(), 0b101);
+        assert_eq!(mock::expiries(), 0);
+    }
+
+    #[test]
+    fn cycles_for_ms_at_common_clocks() {
+        assert_eq!(cycles_for_ms(12_000_000, 1), 12_000);
+        assert_eq!(cycles_for_ms(48_000_000, 1), 48_000);
+        assert_eq!(cycles_for_ms(100_000_000, 1), 100_000);
+        assert_eq!(cycles_for_ms(48_000_000, 0), 0);
+
+        // 1.5 cycles are rounded up
+        assert_eq!(cycles_for_ms(1_500, 1), 2);
+    }
+
+    #[test]
+    fn cycles_for_ms_saturates_past_max_ms() {
+        for &(hz, max_ms) in &[
+            (12_000_000, 357_913),
+            (48_000_000, 89_478),
+            (100_000_000, 42_949),
+        ] {
+            let clock = Clock::new(hz);
+
+            assert_eq!(clock.max_ms(), max_ms);
+            assert!(cycles_for_ms(hz, max_ms) < u32::MAX);
+            assert_eq!(cycles_for_ms(hz, max_ms + 1), u32::MAX);
+            assert_eq!(cycles_for_ms(hz, u32::MAX), u32::MAX);
+        }
+    }
+
+    #[test]
+    fn cycles_for_us_and_us_for_cycles_convert() {
+        assert_eq!(cycles_for_us(12_000_000, 1), 12);
+        assert_eq!(cycles_for_us(48_000_000, 250), 12_000);
+        assert_eq!(us_for_cycles(48_000_000, 12_000), 250);
+
+        // Rounded up in one direction and down in the other
+        assert_eq!(cycles_for_us(1_500_000, 1), 2);
+        assert_eq!(us_for_cycles(1_500_000, 2), 1);
+        assert_eq!(us_for_cycles(48_000_000, 47), 0);
+    }
+
+    #[test]
+    fn cycles_for_us_round_trips_at_whole_megahertz() {
+        for &hz in &[1_000_000, 12_000_000, 48_000_000, 100_000_000] {
+            for &us in &[0, 1, 999, 1_000_000, Clock::new(hz).max_us()] {
+                assert_eq!(us_for_cycles(hz, cycles_for_us(hz, us)), us);
+            }
+        }
+    }
+
+    #[test]
+    fn cycles_for_us_caps_at_the_counter_width() {
+        let clock = Clock::new(100_000_000);
+
+        assert_eq!(cycles_for_us(clock.hz, clock.max_us() + 1), u32::MAX);
+        assert_eq!(cycles_for_us(clock.hz, u32::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn arm_us_loads_the_counter_in_cycles() {
+        mock::reset();
+
+        let clock = Clock::new(48_000_000);
+
+        assert_eq!(clock.arm_us(250), 0);
+        assert_eq!(mock::counter(), 12_000);
+
+        // Reading the counter costs a cycle, which rounds down to 249 us
+        assert_eq!(clock.remaining_us(), 249);
+
+        clock.arm_us(u32::MAX);
+
+        assert_eq!(mock::counter(), u32::MAX);
+    }
+
+    #[test]
+    fn with_timeout_returns_the_result_and_stops_the_timer() {
+        mock::reset();
+
+        let mut polls = 0;
+        let result = with_timeout(1_000, || {
+            polls += 1;
+
+            if polls == 3 {
+                Some(polls)
+            } else {
+                None
+            }
+        });
+
+        assert_eq!(result, Ok(3));
+        assert_eq!(mock::counter(), 0);
+        assert_eq!(mock::expiries(), 0);
+        assert_eq!(mock::pending(), 0);
+    }
+
+    #[test]
+    fn with_timeout_gives_up_once_the_timer_runs_out() {
+        mock::reset();
+        mock::set_poll_cycles(10);
+
+        let mut polls = 0;
+        let result: Result<(), _> = with_timeout(1_000, || {
+            polls += 1;
+
+            None
+        });
+
+        assert_eq!(result, Err(Timeout));
+        assert!(polls > 1);
+        assert_eq!(mock::expiries(), 1);
+        assert_eq!(mock::pending(), 1 << interrupt::TIMER_IRQ);
+    }
+
+    #[test]
+    fn with_timeout_calls_f_once_more_after_the_expiry() {
+        mock::reset();
+
+        let mut expired_before_call = false;
+        let result = with_timeout(10, || {
+            if mock::expiries() == 1 {
+                expired_before_call = true;
+
+                Some(())
+            } else {
+                None
+            }
+        });
+
+        assert_eq!(result, Ok(()));
+        assert!(expired_before_call);
+    }
+
+    #[test]
+    fn extend_pushes_the_deadline_out() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        OneShot::arm(100);
+        mock::advance(40);
+
+        assert_eq!(extend(50), 110);
+        assert_eq!(mock::counter(), 110);
+
+        // Saturates at the counter width
+        OneShot::arm(u32::MAX - 10);
+        assert_eq!(extend(100), u32::MAX);
+    }
+
+    #[test]
+    fn extend_past_expiry_arms_for_the_extension() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        OneShot::arm(10);
+        mock::advance(20);
+        assert_eq!(mock::expiries(), 1);
+
+        assert_eq!(extend(50), 50);
+        mock::advance(50);
+        assert_eq!(mock::expiries(), 2);
+    }
+
+    #[test]
+    fn remaining_counts_down_and_rearms() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        OneShot::arm(1000);
+        mock::advance(300);
+
+        assert_eq!(remaining(), 700);
+        assert_eq!(mock::counter(), 700);
+
+        // Each read costs the poll cycles, which the re-arm doesn't give back
+        mock::set_poll_cycles(5);
+        assert_eq!(remaining(), 695);
+        assert_eq!(remaining(), 690);
+        assert_eq!(mock::counter(), 690);
+    }
+
+    #[test]
+    fn remaining_leaves_a_stopped_timer_stopped() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        assert_eq!(remaining(), 0);
+
+        OneShot::arm(10);
+        mock::advance(10);
+
+        assert_eq!(remaining(), 0);
+        assert_eq!(mock::counter(), 0);
+        mock::advance(100);
+        assert_eq!(mock::expiries(), 1);
+    }
+
+    #[test]
+    fn one_shot_fires_once() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        assert_eq!(OneShot::arm(100), 0);
+        assert_eq!(OneShot::arm(50), 100);
+        mock::advance(50);
+        mock::advance(1_000);
+
+        assert_eq!(mock::expiries(), 1);
+        assert_eq!(mock::pending(), 1 << interrupt::TIMER_IRQ);
+    }
+
+    #[test]
+    fn periodic_reload_compensated_keeps_the_ticks_on_the_grid() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        let tick = Periodic::new(1_000);
+        tick.start();
+
+        // Each tick is handled 30 cycles after it fired, at 1030, 2030, ...
+        // and the next one still fires at the next multiple of 1000
+        mock::advance(1_000);
+
+        for n in 1..=3 {
+            mock::advance(30);
+            tick.reload_compensated(30);
+
+            mock::advance(969);
+            assert_eq!(mock::expiries(), n);
+            mock::advance(1);
+            assert_eq!(mock::expiries(), n + 1);
+        }
+    }
+
+    #[test]
+    fn periodic_reload_counts_from_the_reload() {
+        mock::reset();
+        mock::set_poll_cycles(0);
+
+        let tick = Periodic::new(1_000);
+        tick.start();
+        mock::advance(1_030);
+
+        tick.reload();
+        assert_eq!(mock::counter(), 1_000);
+
+        // An overrun of a period or more fires the next tick straight away
+        tick.reload_compensated(1_000);
+        assert_eq!(mock::counter(), 1);
+        tick.reload_compensated(u32::MAX);
+        assert_eq!(mock::counter(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+//! Tests for the parsing and code generation of the build script
+
+#[path = "../build/config.rs"]
+mod config;
+
+use config::*;
+
+#[test]
+fn linker_fragment_places_the_vector_at_the_default_address() {
+    let fragment = irq_linker_fragment(DEFAULT_VECTOR_ADDR);
+
+    assert!(fragment.contains(".irq_vector 0x00000010 :"));
+    assert!(fragment.contains("KEEP(*(.irq_vector));"));
+}
+
+#[test]
+fn linker_fragment_places_the_vector_at_an_overridden_address() {
+    let fragment = irq_linker_fragment(parse_addr("0x0001_0000").unwrap());
+
+    assert!(fragment.contains(".irq_vector 0x00010000 :"));
+}
+
+#[test]
+fn vector_addresses_parse_in_decimal_and_hexadecimal() {
+    assert_eq!(parse_addr("16"), Some(0x10));
+    assert_eq!(parse_addr(" 0x10 "), Some(0x10));
+    assert_eq!(parse_addr("0XFFFF_FF00"), Some(0xffff_ff00));
+    assert_eq!(parse_addr("0x1_0000_0000"), None);
+    assert_eq!(parse_addr("0x"), None);
+    assert_eq!(parse_addr("ten"), None);
+}
+
+#[test]
+fn custom_opcodes_parse_as_numbers_or_names() {
+    assert_eq!(parse_custom_opcode("0"), Some(0));
+    assert_eq!(parse_custom_opcode("3"), Some(3));
+    assert_eq!(parse_custom_opcode("custom1"), Some(1));
+    assert_eq!(parse_custom_opcode(" CUSTOM_2 "), Some(2));
+}
+
+#[test]
+fn custom_opcodes_past_3_are_rejected() {
+    assert_eq!(parse_custom_opcode("4"), None);
+    assert_eq!(parse_custom_opcode("custom4"), None);
+    assert_eq!(parse_custom_opcode("custom"), None);
+    assert_eq!(parse_custom_opcode("-1"), None);
+}
+
+#[test]
+fn riscv32_and_riscv64_targets_set_their_cfgs() {
+    assert_eq!(
+        target_cfgs("riscv32imc-unknown-none-elf"),
+        Some(&["riscv", "riscv32"][..])
+    );
+    assert_eq!(
+        target_cfgs("riscv64gc-unknown-none-elf"),
+        Some(&["riscv", "riscv64"][..])
+    );
+}
+
+#[test]
+fn host_targets_set_no_cfgs() {
+    let none: &[&str] = &[];
+
+    assert_eq!(target_cfgs("x86_64-unknown-linux-gnu"), Some(none));
+    assert_eq!(target_cfgs("aarch64-apple-darwin"), Some(none));
+}
+
+#[test]
+fn riscv32e_targets_are_riscv32() {
+    assert_eq!(
+        target_cfgs("riscv32e-unknown-none-elf"),
+        Some(&["riscv", "riscv32"][..])
+    );
+    assert_eq!(
+        target_cfgs("riscv32emc-unknown-none-elf"),
+        Some(&["riscv", "riscv32"][..])
+    );
+}
+
+#[test]
+fn other_riscv_targets_are_rejected() {
+    assert_eq!(target_cfgs("riscv128-unknown-none-elf"), None);
+    assert_eq!(target_cfgs("riscv-unknown-none-elf"), None);
+}
@@ -0,0 +1,26 @@
+//! Tests for the entry generated by `#[interrupt]`
+
+#![cfg(all(feature = "macros", feature = "mock-host"))]
+
+use coucal_rs::interrupt;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+static SEEN: AtomicU32 = AtomicU32::new(0);
+static INSIDE: AtomicBool = AtomicBool::new(false);
+
+#[coucal_rs::interrupt]
+fn on_irq(irqs: u32) {
+    SEEN.store(irqs, Ordering::Relaxed);
+    INSIDE.store(interrupt::in_interrupt(), Ordering::Relaxed);
+}
+
+#[test]
+fn the_entry_calls_the_handler_with_the_pending_irqs() {
+    coucal_rs::mock::reset();
+
+    __coucal_irq_dispatch(0b1010);
+
+    assert_eq!(SEEN.load(Ordering::Relaxed), 0b1010);
+    assert!(INSIDE.load(Ordering::Relaxed));
+    assert!(!interrupt::in_interrupt());
+}